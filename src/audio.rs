@@ -0,0 +1,285 @@
+use crate::ay8910::PSG_SAMPLE_RATE;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use log::error;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+// Anything queued beyond this is dropped, so latency can't build up if the host falls behind
+const MAX_QUEUED_MS: usize = 100;
+const WAV_SAMPLE_RATE: u32 = 48000;
+
+pub trait AudioBackend {
+    fn sample_rate(&self) -> u32;
+    fn queue(&mut self, samples: &[f32]);
+}
+
+pub struct NullBackend;
+
+impl AudioBackend for NullBackend {
+    fn sample_rate(&self) -> u32 {
+        WAV_SAMPLE_RATE
+    }
+
+    fn queue(&mut self, _: &[f32]) {}
+}
+
+pub struct CpalBackend {
+    // Playback stops when the stream is dropped
+    _stream: cpal::Stream,
+    buffer: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: u32,
+}
+
+impl CpalBackend {
+    pub fn new() -> Option<Self> {
+        let device = cpal::default_host().default_output_device()?;
+        let config = match device.default_output_config() {
+            Ok(config) => config,
+            Err(err) => {
+                error!("default_output_config() failed: {err}");
+                return None;
+            }
+        };
+        let sample_rate = config.sample_rate().0;
+        let buffer = Arc::new(Mutex::new(VecDeque::new()));
+
+        let stream = match config.sample_format() {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config.config(), buffer.clone()),
+            SampleFormat::I16 => build_stream::<i16>(&device, &config.config(), buffer.clone()),
+            SampleFormat::U16 => build_stream::<u16>(&device, &config.config(), buffer.clone()),
+            format => {
+                error!("Unsupported sample format {format}");
+                return None;
+            }
+        }?;
+        if let Err(err) = stream.play() {
+            error!("stream.play() failed: {err}");
+            return None;
+        }
+
+        Some(Self {
+            _stream: stream,
+            buffer: buffer,
+            sample_rate: sample_rate,
+        })
+    }
+}
+
+fn build_stream<T: SizedSample + FromSample<f32>>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    buffer: Arc<Mutex<VecDeque<f32>>>,
+) -> Option<cpal::Stream> {
+    let channels = config.channels as usize;
+    let mut last_sample = 0.0;
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let mut buffer = buffer.lock().unwrap();
+            for frame in data.chunks_mut(channels) {
+                // Repeat the last sample on underrun instead of clicking back to 0
+                last_sample = buffer.pop_front().unwrap_or(last_sample);
+                for out in frame.iter_mut() {
+                    *out = T::from_sample(last_sample);
+                }
+            }
+        },
+        |err| error!("Audio stream error: {err}"),
+        None,
+    );
+    match stream {
+        Ok(stream) => Some(stream),
+        Err(err) => {
+            error!("build_output_stream() failed: {err}");
+            None
+        }
+    }
+}
+
+impl AudioBackend for CpalBackend {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn queue(&mut self, samples: &[f32]) {
+        let max_len = self.sample_rate as usize * MAX_QUEUED_MS / 1000;
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.extend(samples);
+        if buffer.len() > max_len {
+            let excess = buffer.len() - max_len;
+            buffer.drain(..excess);
+        }
+    }
+}
+
+pub struct WavWriter {
+    file: File,
+    data_len: u32,
+}
+
+impl WavWriter {
+    // 16-bit mono PCM
+    pub fn create(path: &str, sample_rate: u32) -> std::io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = vec![];
+        header.extend(b"RIFF");
+        header.extend(36u32.to_le_bytes());
+        header.extend(b"WAVEfmt ");
+        header.extend(16u32.to_le_bytes());
+        header.extend(1u16.to_le_bytes()); // PCM
+        header.extend(1u16.to_le_bytes()); // channels
+        header.extend(sample_rate.to_le_bytes());
+        header.extend((sample_rate * 2).to_le_bytes()); // byte rate
+        header.extend(2u16.to_le_bytes()); // block align
+        header.extend(16u16.to_le_bytes()); // bits per sample
+        header.extend(b"data");
+        header.extend(0u32.to_le_bytes());
+        file.write_all(&header)?;
+
+        Ok(Self {
+            file: file,
+            data_len: 0,
+        })
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        let mut data = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            let val = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            data.extend(val.to_le_bytes());
+        }
+        self.file.write_all(&data)?;
+        self.data_len += data.len() as u32;
+
+        // Keep the header sizes up to date, so the file is valid whenever the emulator stops
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + self.data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+pub struct WavBackend {
+    wav: WavWriter,
+}
+
+impl WavBackend {
+    pub fn new(path: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            wav: WavWriter::create(path, WAV_SAMPLE_RATE)?,
+        })
+    }
+}
+
+impl AudioBackend for WavBackend {
+    fn sample_rate(&self) -> u32 {
+        WAV_SAMPLE_RATE
+    }
+
+    fn queue(&mut self, samples: &[f32]) {
+        if let Err(err) = self.wav.write(samples) {
+            error!("Writing wav failed: {err}");
+        }
+    }
+}
+
+pub fn open_backend(wav_path: Option<String>) -> Box<dyn AudioBackend> {
+    match wav_path {
+        Some(path) => match WavBackend::new(&path) {
+            Ok(backend) => return Box::new(backend),
+            Err(err) => error!("Unable to create {path}: {err}"),
+        },
+        None => match CpalBackend::new() {
+            Some(backend) => return Box::new(backend),
+            None => error!("No audio output device, continuing without sound"),
+        },
+    }
+    Box::new(NullBackend)
+}
+
+struct Resampler {
+    in_rate: u32,
+    out_rate: u32,
+    phase: u32,
+    acc: f32,
+    count: u32,
+    // DC blocker state, the PSG output is unipolar
+    prev_in: f32,
+    prev_out: f32,
+}
+
+impl Resampler {
+    fn new(in_rate: u32, out_rate: u32) -> Self {
+        Self {
+            in_rate: in_rate,
+            out_rate: out_rate,
+            phase: 0,
+            acc: 0.0,
+            count: 0,
+            prev_in: 0.0,
+            prev_out: 0.0,
+        }
+    }
+
+    fn process(&mut self, samples: &[f32], out: &mut Vec<f32>) {
+        // Box filter: average every input sample that falls within one output period
+        for sample in samples {
+            self.acc += sample;
+            self.count += 1;
+            self.phase += self.out_rate;
+            if self.phase >= self.in_rate {
+                self.phase -= self.in_rate;
+                let avg = self.acc / self.count as f32;
+                self.acc = 0.0;
+                self.count = 0;
+
+                let filtered = avg - self.prev_in + 0.995 * self.prev_out;
+                self.prev_in = avg;
+                self.prev_out = filtered;
+                out.push(filtered);
+            }
+        }
+    }
+}
+
+pub struct Audio {
+    resampler: Resampler,
+    backend: Box<dyn AudioBackend>,
+    out_samples: Vec<f32>,
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl Audio {
+    pub fn new(backend: Box<dyn AudioBackend>) -> Self {
+        Self {
+            resampler: Resampler::new(PSG_SAMPLE_RATE, backend.sample_rate()),
+            backend: backend,
+            out_samples: vec![],
+            volume: 0.5,
+            muted: false,
+        }
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        self.out_samples.clear();
+        self.resampler.process(samples, &mut self.out_samples);
+        let volume = if self.muted { 0.0 } else { self.volume };
+        for sample in self.out_samples.iter_mut() {
+            *sample *= volume;
+        }
+        self.backend.queue(&self.out_samples);
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.menu_button("Audio", |ui| {
+            ui.checkbox(&mut self.muted, "Mute");
+            ui.add(egui::Slider::new(&mut self.volume, 0.0..=1.0).text("Volume"));
+        });
+    }
+}
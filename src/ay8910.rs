@@ -0,0 +1,209 @@
+use crate::constants::{CPU_CLOCK, PSG_CLOCK};
+
+// The tone counters are clocked at PSG_CLOCK / 8, noise and envelope at half of that
+pub const PSG_SAMPLE_RATE: u32 = PSG_CLOCK / 8;
+const CYCLES_PER_TICK: u32 = CPU_CLOCK / PSG_SAMPLE_RATE;
+
+const REG_MIXER: usize = 7;
+const REG_AMP_A: usize = 8;
+const REG_ENV_FINE: usize = 11;
+const REG_ENV_COARSE: usize = 12;
+const REG_ENV_SHAPE: usize = 13;
+const REG_PORT_A: usize = 14;
+const REG_PORT_B: usize = 15;
+
+// Bits that are actually present in each register, the rest read back as 0
+const REG_MASKS: [u8; 16] = [
+    0xff, 0x0f, 0xff, 0x0f, 0xff, 0x0f, 0x1f, 0xff, 0x1f, 0x1f, 0x1f, 0xff, 0xff, 0x0f, 0xff, 0xff,
+];
+
+// Logarithmic DAC levels, normalized to 1.0
+const VOLUMES: [f32; 16] = [
+    0.0, 0.0106, 0.0150, 0.0222, 0.0320, 0.0466, 0.0665, 0.1039, 0.1237, 0.1986, 0.2803, 0.3548,
+    0.4702, 0.6030, 0.7530, 1.0,
+];
+
+#[derive(Savefile)]
+pub struct AY8910 {
+    addr: u8,
+    regs: [u8; 16],
+    pub port_a_in: u8,
+    pub port_b_in: u8,
+
+    tone_counters: [u16; 3],
+    tone_outputs: [bool; 3],
+    noise_counter: u8,
+    noise_prescale: bool,
+    rng: u32,
+    env_counter: u16,
+    env_step: i8,
+    env_attack: u8,
+    env_hold: bool,
+    env_alternate: bool,
+    env_holding: bool,
+
+    cycles: u32,
+    pub samples: Vec<f32>,
+}
+
+impl AY8910 {
+    pub fn new() -> Self {
+        Self {
+            addr: 0,
+            regs: [0; 16],
+            port_a_in: 0xff,
+            port_b_in: 0xff,
+
+            tone_counters: [0; 3],
+            tone_outputs: [false; 3],
+            noise_counter: 0,
+            noise_prescale: false,
+            rng: 1,
+            env_counter: 0,
+            env_step: 0,
+            env_attack: 0,
+            env_hold: false,
+            env_alternate: false,
+            env_holding: true,
+
+            cycles: 0,
+            samples: vec![],
+        }
+    }
+
+    pub fn set_addr(&mut self, value: u8) {
+        // Upper nibble acts as a chip select on the real part
+        if value & 0xf0 == 0 {
+            self.addr = value;
+        }
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        let reg = self.addr as usize;
+        self.regs[reg] = value & REG_MASKS[reg];
+        if reg == REG_ENV_SHAPE {
+            self.restart_envelope();
+        }
+    }
+
+    pub fn read_data(&self) -> u8 {
+        /*
+         * R7 bit 6: port A output enable
+         * R7 bit 7: port B output enable
+         * Ports set as input return what is on their pins instead of the latch
+         */
+        let reg = self.addr as usize;
+        match reg {
+            REG_PORT_A if self.regs[REG_MIXER] & 0x40 == 0 => self.port_a_in,
+            REG_PORT_B if self.regs[REG_MIXER] & 0x80 == 0 => self.port_b_in,
+            _ => self.regs[reg],
+        }
+    }
+
+    fn restart_envelope(&mut self) {
+        /*
+         * bit 3: continue
+         * bit 2: attack
+         * bit 1: alternate
+         * bit 0: hold
+         */
+        let shape = self.regs[REG_ENV_SHAPE];
+        self.env_attack = if shape & 4 != 0 { 0x0f } else { 0 };
+        if shape & 8 == 0 {
+            // Shapes without continue are equivalent to holding at 0 after the first ramp
+            self.env_hold = true;
+            self.env_alternate = self.env_attack != 0;
+        } else {
+            self.env_hold = shape & 1 != 0;
+            self.env_alternate = shape & 2 != 0;
+        }
+        self.env_step = 0x0f;
+        self.env_holding = false;
+        self.env_counter = 0;
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        let period = ((self.regs[channel * 2 + 1] as u16) << 8) | self.regs[channel * 2] as u16;
+        period.max(1)
+    }
+
+    fn env_period(&self) -> u16 {
+        let period = ((self.regs[REG_ENV_COARSE] as u16) << 8) | self.regs[REG_ENV_FINE] as u16;
+        period.max(1)
+    }
+
+    fn step_envelope(&mut self) {
+        if self.env_holding {
+            return;
+        }
+        self.env_step -= 1;
+        if self.env_step < 0 {
+            if self.env_hold {
+                if self.env_alternate {
+                    self.env_attack ^= 0x0f;
+                }
+                self.env_holding = true;
+                self.env_step = 0;
+            } else {
+                if self.env_alternate {
+                    self.env_attack ^= 0x0f;
+                }
+                self.env_step &= 0x0f;
+            }
+        }
+    }
+
+    fn tick_once(&mut self) {
+        for channel in 0..3 {
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= self.tone_period(channel) {
+                self.tone_counters[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        self.noise_prescale = !self.noise_prescale;
+        if self.noise_prescale {
+            self.noise_counter += 1;
+            if self.noise_counter >= self.regs[6].max(1) {
+                self.noise_counter = 0;
+                // 17-bit LFSR, taps at bits 0 and 3
+                let feedback = (self.rng ^ (self.rng >> 3)) & 1;
+                self.rng = (self.rng >> 1) | (feedback << 16);
+            }
+
+            self.env_counter += 1;
+            if self.env_counter >= self.env_period() {
+                self.env_counter = 0;
+                self.step_envelope();
+            }
+        }
+
+        let env_volume = (self.env_step as u8 & 0x0f) ^ self.env_attack;
+        let noise_out = self.rng & 1 != 0;
+        let mixer = self.regs[REG_MIXER];
+        let mut sample = 0.0;
+        for channel in 0..3 {
+            let tone_off = mixer & (1 << channel) != 0;
+            let noise_off = mixer & (8 << channel) != 0;
+            if (self.tone_outputs[channel] || tone_off) && (noise_out || noise_off) {
+                let amp = self.regs[REG_AMP_A + channel];
+                let volume = if amp & 0x10 != 0 { env_volume } else { amp };
+                sample += VOLUMES[volume as usize];
+            }
+        }
+        self.samples.push(sample / 3.0);
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_TICK {
+            self.cycles -= CYCLES_PER_TICK;
+            self.tick_once();
+        }
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
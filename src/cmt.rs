@@ -0,0 +1,454 @@
+use crate::audio::WavWriter;
+use crate::constants::CPU_CLOCK;
+use egui::Context;
+use log::error;
+use std::fs::File;
+use std::io::Write;
+
+// Speed of fast forward and rewind relative to play
+const WIND_SPEED: u64 = 16;
+// APSS skips to the next silent gap of at least this long
+const APSS_GAP_SECS: u32 = 4;
+const RECORD_SAMPLE_RATE: u32 = 16000;
+
+/*
+ * Sub CPU CMT control commands (e9 xx)
+ */
+const CMT_EJECT: u8 = 0x00;
+const CMT_STOP: u8 = 0x01;
+const CMT_PLAY: u8 = 0x02;
+const CMT_FAST_FORWARD: u8 = 0x03;
+const CMT_REWIND: u8 = 0x04;
+const CMT_APSS_FAST_FORWARD: u8 = 0x05;
+const CMT_APSS_REWIND: u8 = 0x06;
+const CMT_RECORD: u8 = 0x0a;
+
+#[derive(Clone, Copy, PartialEq, Savefile)]
+pub enum CMTState {
+    Stopped,
+    Playing,
+    FastForward,
+    Rewinding,
+    Recording,
+}
+
+#[derive(Savefile)]
+pub struct Tape {
+    name: String,
+    write_protected: bool,
+    sample_rate: u32,
+    num_samples: u32,
+    // 1 bit per sample of the signal level, msb first
+    data: Vec<u8>,
+}
+
+impl Tape {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        /*
+         * New format:
+         * $00: "TAPE"
+         * $04: tape name, nul terminated (17 bytes)
+         * $15: reserved (5 bytes)
+         * $1a: write protect ($10 if protected)
+         * $1b: format ($01 = sampled at a fixed interval)
+         * $1c: sample rate in Hz
+         * $20: data size in bits
+         * $24: tape position in bits
+         * $28: data
+         *
+         * Old format:
+         * $00: sample rate in Hz
+         * $04: data
+         */
+        let read_u32 = |offs: usize| u32::from_le_bytes(data[offs..offs + 4].try_into().unwrap());
+
+        let tape = if data.len() >= 0x28 && &data[0..4] == b"TAPE" {
+            if data[0x1b] != 0x01 {
+                return Err(format!("Unsupported TAP format {:02x}", data[0x1b]));
+            }
+            let name_bytes = &data[0x04..0x15];
+            let name_len = name_bytes.iter().position(|&b| b == 0).unwrap_or(17);
+            let tape_data = data[0x28..].to_vec();
+            let num_samples = read_u32(0x20).min(tape_data.len() as u32 * 8);
+            Self {
+                name: String::from_utf8_lossy(&name_bytes[..name_len]).to_string(),
+                write_protected: data[0x1a] & 0x10 != 0,
+                sample_rate: read_u32(0x1c),
+                num_samples: num_samples,
+                data: tape_data,
+            }
+        } else if data.len() >= 4 {
+            let tape_data = data[4..].to_vec();
+            Self {
+                name: String::new(),
+                write_protected: false,
+                sample_rate: read_u32(0),
+                num_samples: tape_data.len() as u32 * 8,
+                data: tape_data,
+            }
+        } else {
+            return Err(String::from("TAP file too short"));
+        };
+
+        if tape.sample_rate == 0 {
+            return Err(String::from("TAP sample rate is 0"));
+        }
+        Ok(tape)
+    }
+
+    pub fn blank() -> Self {
+        Self {
+            name: String::new(),
+            write_protected: false,
+            sample_rate: RECORD_SAMPLE_RATE,
+            num_samples: 0,
+            data: vec![],
+        }
+    }
+
+    pub fn to_tap(&self) -> Vec<u8> {
+        let mut ret = vec![];
+        ret.extend(b"TAPE");
+        let mut name = [0u8; 17];
+        let name_len = self.name.len().min(16);
+        name[..name_len].copy_from_slice(&self.name.as_bytes()[..name_len]);
+        ret.extend(name);
+        ret.extend([0u8; 5]);
+        ret.push(if self.write_protected { 0x10 } else { 0x00 });
+        ret.push(0x01);
+        ret.extend(self.sample_rate.to_le_bytes());
+        ret.extend(self.num_samples.to_le_bytes());
+        ret.extend(0u32.to_le_bytes());
+        ret.extend(&self.data);
+        ret
+    }
+
+    pub fn write_wav(&self, path: &str) -> std::io::Result<()> {
+        let mut wav = WavWriter::create(path, self.sample_rate)?;
+        let samples: Vec<f32> = (0..self.num_samples)
+            .map(|pos| if self.level(pos) { 0.5 } else { -0.5 })
+            .collect();
+        wav.write(&samples)
+    }
+
+    fn level(&self, pos: u32) -> bool {
+        if pos >= self.num_samples {
+            return false;
+        }
+        (self.data[(pos / 8) as usize] >> (7 - pos % 8)) & 1 != 0
+    }
+
+    fn set_level(&mut self, pos: u32, level: bool) {
+        let offs = (pos / 8) as usize;
+        if offs >= self.data.len() {
+            self.data.resize(offs + 1, 0);
+        }
+        let mask = 0x80 >> (pos % 8);
+        if level {
+            self.data[offs] |= mask;
+        } else {
+            self.data[offs] &= !mask;
+        }
+        self.num_samples = self.num_samples.max(pos + 1);
+    }
+}
+
+#[derive(Savefile)]
+pub struct CMT {
+    tape: Option<Tape>,
+    state: CMTState,
+    position: u32,
+    // CPU cycles * sample rate, to advance the tape at the sample rate
+    cycle_acc: u64,
+    current_cmd: u8,
+    stop_pulse: bool,
+    // Cassette output data from 8255 port C bit 0
+    output_level: bool,
+
+    status_open: bool,
+}
+
+impl CMT {
+    pub fn new() -> Self {
+        Self {
+            tape: None,
+            state: CMTState::Stopped,
+            position: 0,
+            cycle_acc: 0,
+            current_cmd: 0,
+            stop_pulse: false,
+            output_level: false,
+
+            status_open: false,
+        }
+    }
+
+    pub fn insert(&mut self, data: Vec<u8>) -> Result<(), String> {
+        self.tape = Some(Tape::parse(&data)?);
+        self.state = CMTState::Stopped;
+        self.position = 0;
+        self.cycle_acc = 0;
+        Ok(())
+    }
+
+    pub fn insert_blank(&mut self) {
+        self.tape = Some(Tape::blank());
+        self.state = CMTState::Stopped;
+        self.position = 0;
+        self.cycle_acc = 0;
+    }
+
+    pub fn eject(&mut self) {
+        self.tape = None;
+        self.state = CMTState::Stopped;
+        self.position = 0;
+    }
+
+    fn stop(&mut self) {
+        self.state = CMTState::Stopped;
+        self.stop_pulse = true;
+    }
+
+    pub fn command(&mut self, cmd: u8) {
+        self.current_cmd = cmd;
+        if self.tape.is_none() {
+            return;
+        }
+        match cmd {
+            // Software eject only stops the deck, so the image isn't lost
+            CMT_EJECT | CMT_STOP => self.stop(),
+            CMT_PLAY => self.state = CMTState::Playing,
+            CMT_FAST_FORWARD => self.state = CMTState::FastForward,
+            CMT_REWIND => self.state = CMTState::Rewinding,
+            CMT_APSS_FAST_FORWARD => self.apss(true),
+            CMT_APSS_REWIND => self.apss(false),
+            CMT_RECORD => {
+                if self.tape_status() & 0x04 != 0 {
+                    self.state = CMTState::Recording;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn apss(&mut self, forward: bool) {
+        let tape = match &self.tape {
+            Some(tape) => tape,
+            None => return,
+        };
+        let gap_len = tape.sample_rate * APSS_GAP_SECS;
+        let mut pos = self.position;
+        let mut run = 0;
+        let mut prev = tape.level(pos);
+        loop {
+            if forward {
+                if pos + 1 >= tape.num_samples {
+                    break;
+                }
+                pos += 1;
+            } else {
+                if pos == 0 {
+                    break;
+                }
+                pos -= 1;
+            }
+            let level = tape.level(pos);
+            if level == prev {
+                run += 1;
+                if run >= gap_len {
+                    break;
+                }
+            } else {
+                run = 0;
+                prev = level;
+            }
+        }
+        self.position = pos;
+        self.stop();
+    }
+
+    pub fn tape(&self) -> Option<&Tape> {
+        self.tape.as_ref()
+    }
+
+    pub fn current_cmd(&self) -> u8 {
+        self.current_cmd
+    }
+
+    pub fn tape_status(&self) -> u8 {
+        /*
+         * bit 2: set if recording is allowed
+         * bit 1: set if a tape is inserted
+         * bit 0: clear at the end of the tape
+         */
+        match &self.tape {
+            None => 0x05,
+            Some(tape) => {
+                let mut ret = 0x02;
+                if self.position < tape.num_samples {
+                    ret |= 0x01;
+                }
+                if !tape.write_protected {
+                    ret |= 0x04;
+                }
+                ret
+            }
+        }
+    }
+
+    pub fn read_bit(&self) -> bool {
+        match &self.tape {
+            Some(tape) if self.state == CMTState::Playing => tape.level(self.position),
+            _ => false,
+        }
+    }
+
+    pub fn set_output(&mut self, level: bool) {
+        self.output_level = level;
+    }
+
+    // Low once after a stop, high again after being read
+    pub fn test_bit(&mut self, side_effects: bool) -> bool {
+        let ret = !self.stop_pulse;
+        if side_effects {
+            self.stop_pulse = false;
+        }
+        ret
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        let (sample_rate, num_samples) = match &self.tape {
+            Some(tape) => (tape.sample_rate, tape.num_samples),
+            None => return,
+        };
+        let speed = match self.state {
+            CMTState::Stopped => return,
+            CMTState::Playing | CMTState::Recording => 1,
+            CMTState::FastForward | CMTState::Rewinding => WIND_SPEED,
+        };
+        self.cycle_acc += cycles as u64 * sample_rate as u64 * speed;
+        let samples = (self.cycle_acc / CPU_CLOCK as u64) as u32;
+        self.cycle_acc %= CPU_CLOCK as u64;
+
+        match self.state {
+            CMTState::Recording => {
+                let tape = self.tape.as_mut().unwrap();
+                for _ in 0..samples {
+                    tape.set_level(self.position, self.output_level);
+                    self.position += 1;
+                }
+            }
+            CMTState::Rewinding => {
+                if samples >= self.position {
+                    self.position = 0;
+                    self.stop();
+                } else {
+                    self.position -= samples;
+                }
+            }
+            _ => {
+                self.position = self.position.saturating_add(samples);
+                if self.position >= num_samples {
+                    self.position = num_samples;
+                    self.stop();
+                }
+            }
+        }
+    }
+
+    fn save_tap(&self, tape: &Tape) {
+        let res = tinyfiledialogs::save_file_dialog("Save tape as TAP", "./tape.tap");
+        if let Some(fname) = res {
+            let res = File::create(&fname).and_then(|mut f| f.write_all(&tape.to_tap()));
+            if let Err(err) = res {
+                error!("Unable to save {fname}: {err}");
+            }
+        }
+    }
+
+    fn save_wav(&self, tape: &Tape) {
+        let res = tinyfiledialogs::save_file_dialog("Save tape as WAV", "./tape.wav");
+        if let Some(fname) = res {
+            if let Err(err) = tape.write_wav(&fname) {
+                error!("Unable to save {fname}: {err}");
+            }
+        }
+    }
+
+    pub fn ui(&mut self, ctx: &Context, ui: &mut egui::Ui) {
+        ui.menu_button("CMT", |ui| {
+            if ui.button("Status").clicked() {
+                self.status_open = true;
+                ui.close_menu();
+            }
+            if ui.button("Play").clicked() {
+                self.command(CMT_PLAY);
+                ui.close_menu();
+            }
+            if ui.button("Stop").clicked() {
+                self.command(CMT_STOP);
+                ui.close_menu();
+            }
+            if ui.button("Fast forward").clicked() {
+                self.command(CMT_FAST_FORWARD);
+                ui.close_menu();
+            }
+            if ui.button("Rewind").clicked() {
+                self.command(CMT_REWIND);
+                ui.close_menu();
+            }
+            if ui.button("Record").clicked() {
+                self.command(CMT_RECORD);
+                ui.close_menu();
+            }
+            ui.separator();
+            if ui.button("New blank tape").clicked() {
+                self.insert_blank();
+                ui.close_menu();
+            }
+            if let Some(tape) = &self.tape {
+                if ui.button("Save tape as TAP").clicked() {
+                    self.save_tap(tape);
+                    ui.close_menu();
+                }
+                if ui.button("Save tape as WAV").clicked() {
+                    self.save_wav(tape);
+                    ui.close_menu();
+                }
+            }
+            if ui.button("Eject").clicked() {
+                self.eject();
+                ui.close_menu();
+            }
+        });
+
+        egui::Window::new("CMT Status")
+            .open(&mut self.status_open)
+            .show(ctx, |ui| match &self.tape {
+                None => {
+                    ui.label("No tape inserted");
+                }
+                Some(tape) => {
+                    ui.label(format!("Name: {}", tape.name));
+                    ui.label(format!(
+                        "State: {}",
+                        match self.state {
+                            CMTState::Stopped => "Stopped",
+                            CMTState::Playing => "Playing",
+                            CMTState::FastForward => "Fast forward",
+                            CMTState::Rewinding => "Rewinding",
+                            CMTState::Recording => "Recording",
+                        }
+                    ));
+                    let rate = tape.sample_rate.max(1);
+                    ui.label(format!(
+                        "Position: {}.{:02}s / {}s",
+                        self.position / rate,
+                        (self.position % rate) * 100 / rate,
+                        tape.num_samples / rate
+                    ));
+                    ui.label(format!("Write protected: {}", tape.write_protected));
+                }
+            });
+    }
+}
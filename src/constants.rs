@@ -1,6 +1,6 @@
 pub const SCREEN_WIDTH: u32 = 640;
-pub const DISPLAY_WIDTH: u32 = 640;
 pub const SCREEN_HEIGHT: u32 = 200;
-pub const DISPLAY_HEIGHT: u32 = 200;
 pub const MAIN_CLOCK: u32 = 16_000_000;
 pub const CPU_CLOCK: u32 = MAIN_CLOCK / 4;
+pub const PSG_CLOCK: u32 = MAIN_CLOCK / 8;
+pub const VDP_CLOCK: u32 = 42_954_545;
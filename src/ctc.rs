@@ -0,0 +1,243 @@
+use egui::Context;
+
+/*
+ * Channel control word
+ * x--- ---- interrupt enable
+ * -x-- ---- mode (0=timer, 1=counter)
+ * --x- ---- timer prescaler (0=16, 1=256)
+ * ---x ---- CLK/TRG edge (0=falling, 1=rising)
+ * ---- x--- timer trigger (0=start on time constant load, 1=start on CLK/TRG pulse)
+ * ---- -x-- time constant follows
+ * ---- --x- software reset
+ * ---- ---x control word (0=interrupt vector, channel 0 only)
+ */
+const CTRL_IRQ_ENABLE: u8 = 0x80;
+const CTRL_COUNTER_MODE: u8 = 0x40;
+const CTRL_PRESCALER_256: u8 = 0x20;
+const CTRL_TRIGGER_START: u8 = 0x08;
+const CTRL_CONSTANT_FOLLOWS: u8 = 0x04;
+const CTRL_RESET: u8 = 0x02;
+const CTRL_CONTROL_WORD: u8 = 0x01;
+
+#[derive(Savefile)]
+pub struct CTCChannel {
+    control: u8,
+    time_constant: u16,
+    down_counter: u16,
+    prescale_counter: u16,
+    waiting_for_constant: bool,
+    waiting_for_trigger: bool,
+    running: bool,
+    int_pending: bool,
+    int_in_service: bool,
+}
+
+impl CTCChannel {
+    fn new() -> Self {
+        Self {
+            control: CTRL_RESET,
+            time_constant: 0x100,
+            down_counter: 0x100,
+            prescale_counter: 0,
+            waiting_for_constant: false,
+            waiting_for_trigger: false,
+            running: false,
+            int_pending: false,
+            int_in_service: false,
+        }
+    }
+
+    fn prescaler(&self) -> u16 {
+        if self.control & CTRL_PRESCALER_256 != 0 {
+            256
+        } else {
+            16
+        }
+    }
+
+    fn set_ctrl(&mut self, value: u8) {
+        self.control = value;
+        if value & CTRL_IRQ_ENABLE == 0 {
+            self.int_pending = false;
+        }
+        if value & CTRL_CONSTANT_FOLLOWS != 0 {
+            self.waiting_for_constant = true;
+        }
+        if value & CTRL_RESET != 0 {
+            self.running = false;
+            self.waiting_for_trigger = false;
+        }
+    }
+
+    fn set_time_constant(&mut self, value: u8) {
+        self.waiting_for_constant = false;
+        self.time_constant = if value == 0 { 0x100 } else { value as u16 };
+        if self.running || self.waiting_for_trigger {
+            // The new constant takes effect on the next reload
+            return;
+        }
+        self.down_counter = self.time_constant;
+        self.prescale_counter = 0;
+        if self.control & CTRL_COUNTER_MODE == 0 && self.control & CTRL_TRIGGER_START != 0 {
+            self.waiting_for_trigger = true;
+        } else {
+            self.running = true;
+        }
+    }
+
+    // Returns true when the channel hits zero and pulses ZC/TO
+    fn count(&mut self) -> bool {
+        self.down_counter -= 1;
+        if self.down_counter != 0 {
+            return false;
+        }
+        self.down_counter = self.time_constant;
+        if self.control & CTRL_IRQ_ENABLE != 0 {
+            self.int_pending = true;
+        }
+        true
+    }
+}
+
+#[derive(Savefile)]
+pub struct CTC {
+    channels: [CTCChannel; 4],
+    vector: u8,
+
+    status_open: bool,
+}
+
+impl CTC {
+    pub fn new() -> Self {
+        Self {
+            channels: [
+                CTCChannel::new(),
+                CTCChannel::new(),
+                CTCChannel::new(),
+                CTCChannel::new(),
+            ],
+            vector: 0,
+
+            status_open: false,
+        }
+    }
+
+    pub fn read(&self, channel: usize) -> u8 {
+        // A time constant of 256 is stored as 0x100
+        self.channels[channel].down_counter as u8
+    }
+
+    pub fn write(&mut self, channel: usize, value: u8) {
+        let chan = &mut self.channels[channel];
+        if chan.waiting_for_constant {
+            chan.set_time_constant(value);
+        } else if value & CTRL_CONTROL_WORD != 0 {
+            chan.set_ctrl(value);
+        } else if channel == 0 {
+            self.vector = value & 0xf8;
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        for channel in 0..4 {
+            let chan = &mut self.channels[channel];
+            if !chan.running || chan.control & CTRL_COUNTER_MODE != 0 {
+                continue;
+            }
+            chan.prescale_counter += cycles as u16;
+            let prescaler = chan.prescaler();
+            let mut zero_counts = 0;
+            while chan.prescale_counter >= prescaler {
+                chan.prescale_counter -= prescaler;
+                if chan.count() {
+                    zero_counts += 1;
+                }
+            }
+            for _ in 0..zero_counts {
+                self.zero_count(channel);
+            }
+        }
+    }
+
+    fn zero_count(&mut self, channel: usize) {
+        // ZC/TO0 is wired to CLK/TRG3
+        if channel == 0 {
+            self.trigger(3);
+        }
+    }
+
+    pub fn trigger(&mut self, channel: usize) {
+        let chan = &mut self.channels[channel];
+        if chan.waiting_for_trigger {
+            chan.waiting_for_trigger = false;
+            chan.running = true;
+            return;
+        }
+        if chan.running && chan.control & CTRL_COUNTER_MODE != 0 && chan.count() {
+            self.zero_count(channel);
+        }
+    }
+
+    /*
+     * Daisy chain, channel 0 has the highest priority.
+     * A channel in service blocks every channel after it until its RETI.
+     */
+    pub fn irq_vector(&self) -> Option<u8> {
+        for (i, chan) in self.channels.iter().enumerate() {
+            if chan.int_in_service {
+                return None;
+            }
+            if chan.int_pending {
+                return Some(self.vector | ((i as u8) << 1));
+            }
+        }
+        None
+    }
+
+    pub fn irq_ack(&mut self) {
+        if let Some(chan) = self.channels.iter_mut().find(|chan| chan.int_pending) {
+            chan.int_pending = false;
+            chan.int_in_service = true;
+        }
+    }
+
+    pub fn irq_reti(&mut self) {
+        if let Some(chan) = self.channels.iter_mut().find(|chan| chan.int_in_service) {
+            chan.int_in_service = false;
+        }
+    }
+
+    pub fn ui(&mut self, ctx: &Context, ui: &mut egui::Ui) {
+        ui.menu_button("CTC", |ui| {
+            if ui.button("Status").clicked() {
+                self.status_open = true;
+                ui.close_menu();
+            }
+        });
+
+        egui::Window::new("CTC Status")
+            .open(&mut self.status_open)
+            .show(ctx, |ui| {
+                ui.label(format!("Vector: {:02x}", self.vector));
+                for (i, chan) in self.channels.iter().enumerate() {
+                    ui.separator();
+                    ui.label(format!(
+                        "Channel {}: {}",
+                        i,
+                        if chan.control & CTRL_COUNTER_MODE != 0 {
+                            "counter"
+                        } else {
+                            "timer"
+                        }
+                    ));
+                    ui.label(format!("Control: {:02x}", chan.control));
+                    ui.label(format!("Time constant: {:03x}", chan.time_constant));
+                    ui.label(format!("Down counter: {:03x}", chan.down_counter));
+                    ui.label(format!(
+                        "Running: {}, IRQ pending: {}, in service: {}",
+                        chan.running, chan.int_pending, chan.int_in_service
+                    ));
+                }
+            });
+    }
+}
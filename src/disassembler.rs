@@ -940,7 +940,7 @@ impl Disassembler {
                     .text_style(MONOSPACE.clone()),
             );
             ui.label(
-                RichText::new(format!("{:02x}", io.key_irq_vector))
+                RichText::new(format!("{:02x}", io.subcpu.key_irq_vector))
                     .color(WHITE_COLOR)
                     .text_style(MONOSPACE.clone()),
             );
@@ -0,0 +1,443 @@
+const D88_HEADER_SIZE: usize = 0x2b0;
+const D88_SECTOR_HEADER_SIZE: usize = 0x10;
+const D88_MAX_TRACKS: usize = 164;
+
+// Written next to the source image when it shouldn't be modified
+const OVERLAY_EXT: &str = "ovl";
+
+// Raw 2D images: 40 cylinders, 2 sides, 16 sectors of 256 bytes
+const RAW_SECTORS_PER_TRACK: usize = 16;
+const RAW_SECTOR_SIZE: usize = 0x100;
+
+// Unformatted bytes per revolution with MFM encoding
+const TRACK_LEN_DD: usize = 6250;
+const TRACK_LEN_HD: usize = 10416;
+
+// CRC of the 3 $a1 sync bytes, which precede every address mark
+const CRC_AFTER_SYNC: u16 = 0xcdb4;
+
+pub fn crc16(init: u16, data: &[u8]) -> u16 {
+    // CRC-CCITT, as used in address and data fields
+    let mut crc = init;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[derive(Clone, Copy, PartialEq, Savefile)]
+pub enum DiskFormat {
+    D88,
+    Raw2D,
+}
+
+#[derive(Savefile)]
+pub struct Sector {
+    // ID field
+    pub c: u8,
+    pub h: u8,
+    pub r: u8,
+    pub n: u8,
+    // D88 density byte, $00 = double, $40 = single
+    pub density: u8,
+    pub deleted: bool,
+    // FDC status recorded when the image was dumped, 0 if the sector read fine
+    pub status: u8,
+    pub data: Vec<u8>,
+}
+
+impl Sector {
+    pub fn id_crc(&self) -> u16 {
+        crc16(CRC_AFTER_SYNC, &[0xfe, self.c, self.h, self.r, self.n])
+    }
+}
+
+#[derive(Savefile)]
+pub struct Track {
+    pub sectors: Vec<Sector>,
+}
+
+impl Track {
+    pub fn to_raw(&self, track_len: usize) -> Vec<u8> {
+        self.layout(track_len).0
+    }
+
+    // Byte offsets of each sector's ID field and data field within the raw track
+    pub fn sector_offsets(&self) -> Vec<(usize, usize)> {
+        self.layout(0).1
+    }
+
+    fn layout(&self, track_len: usize) -> (Vec<u8>, Vec<(usize, usize)>) {
+        // IBM System 34 layout, as it'd be returned by read track
+        let mut raw = vec![];
+        let mut offsets = vec![];
+        let push_mark = |raw: &mut Vec<u8>, mark: u8| {
+            raw.extend([0x00; 12]);
+            raw.extend([0xa1; 3]);
+            raw.push(mark);
+        };
+        raw.extend([0x4e; 80]);
+        raw.extend([0x00; 12]);
+        raw.extend([0xc2, 0xc2, 0xc2, 0xfc]);
+        raw.extend([0x4e; 50]);
+        for sector in &self.sectors {
+            push_mark(&mut raw, 0xfe);
+            let id_offs = raw.len();
+            raw.extend([sector.c, sector.h, sector.r, sector.n]);
+            raw.extend(sector.id_crc().to_be_bytes());
+            raw.extend([0x4e; 22]);
+
+            let mark = if sector.deleted { 0xf8 } else { 0xfb };
+            push_mark(&mut raw, mark);
+            offsets.push((id_offs, raw.len()));
+            raw.extend(&sector.data);
+            let crc = crc16(crc16(CRC_AFTER_SYNC, &[mark]), &sector.data);
+            raw.extend(crc.to_be_bytes());
+            raw.extend([0x4e; 54]);
+        }
+        raw.resize(track_len.max(raw.len()), 0x4e);
+        (raw, offsets)
+    }
+
+    pub fn from_raw(raw: &[u8]) -> Self {
+        /*
+         * Parses the bytes sent by write track, where
+         * $f5 writes an $a1 sync byte
+         * $f6 writes a $c2 sync byte
+         * $f7 writes the 2 CRC bytes
+         * ID marks are followed by C, H, R, N and data marks by 128 << N bytes
+         */
+        let mut sectors: Vec<Sector> = vec![];
+        let mut id: Option<(u8, u8, u8, u8)> = None;
+        let mut i = 0;
+        while i < raw.len() {
+            let after_sync = i > 0 && raw[i - 1] == 0xf5;
+            match raw[i] {
+                0xfe if after_sync && i + 4 < raw.len() => {
+                    id = Some((raw[i + 1], raw[i + 2], raw[i + 3], raw[i + 4]));
+                    i += 5;
+                    continue;
+                }
+                0xfb | 0xf8 if after_sync => {
+                    if let Some((c, h, r, n)) = id.take() {
+                        let size = 128usize << (n & 3);
+                        let end = (i + 1 + size).min(raw.len());
+                        let mut data = raw[i + 1..end].to_vec();
+                        data.resize(size, 0);
+                        sectors.push(Sector {
+                            c: c,
+                            h: h,
+                            r: r,
+                            n: n,
+                            density: 0,
+                            deleted: raw[i] == 0xf8,
+                            status: 0,
+                            data: data,
+                        });
+                        i = end;
+                        continue;
+                    }
+                }
+                _ => (),
+            }
+            i += 1;
+        }
+        Self { sectors: sectors }
+    }
+}
+
+#[derive(Savefile)]
+pub struct Disk {
+    pub name: String,
+    pub format: DiskFormat,
+    // Set from the image, toggling it only lasts for the session
+    pub write_protected: bool,
+    header_write_protected: bool,
+    media_type: u8,
+    // Indexed by cylinder * 2 + side
+    tracks: Vec<Track>,
+
+    /*
+    File the image was loaded from, empty if it wasn't loaded from one. Neither is kept in save
+    states, a disk loaded from one is detached so it can't overwrite newer writes to the file.
+    */
+    #[savefile_ignore]
+    pub path: String,
+    pub use_overlay: bool,
+    #[savefile_ignore]
+    pub modified: bool,
+}
+
+fn overlay_path(path: &str) -> String {
+    format!("{}.{}", path, OVERLAY_EXT)
+}
+
+impl Disk {
+    pub fn open(path: &str) -> Result<Self, String> {
+        // Writes from a previous session take priority over the original image
+        let overlay = overlay_path(path);
+        let use_overlay = std::path::Path::new(&overlay).exists();
+        let src = if use_overlay { &overlay } else { path };
+        let data = std::fs::read(src).map_err(|err| err.to_string())?;
+        let mut disk = Self::from_bytes(&data)?;
+        disk.path = path.to_string();
+        disk.use_overlay = use_overlay;
+        Ok(disk)
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if !self.modified || self.path.is_empty() {
+            return Ok(());
+        }
+        let path = if self.use_overlay {
+            overlay_path(&self.path)
+        } else {
+            self.path.clone()
+        };
+        std::fs::write(path, self.to_bytes())?;
+        self.modified = false;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self.format {
+            DiskFormat::D88 => self.to_d88(),
+            DiskFormat::Raw2D => self.to_raw_2d(),
+        }
+    }
+
+    fn to_d88(&self) -> Vec<u8> {
+        let mut data = vec![0u8; D88_HEADER_SIZE];
+        let name = self.name.as_bytes();
+        let name_len = name.len().min(0x10);
+        data[..name_len].copy_from_slice(&name[..name_len]);
+        data[0x1a] = if self.header_write_protected { 0x10 } else { 0 };
+        data[0x1b] = self.media_type;
+
+        for (i, track) in self.tracks.iter().take(D88_MAX_TRACKS).enumerate() {
+            if track.sectors.is_empty() {
+                continue;
+            }
+            let offs = data.len() as u32;
+            data[0x20 + i * 4..0x24 + i * 4].copy_from_slice(&offs.to_le_bytes());
+            for sector in &track.sectors {
+                data.extend([sector.c, sector.h, sector.r, sector.n]);
+                data.extend((track.sectors.len() as u16).to_le_bytes());
+                data.extend([
+                    sector.density,
+                    if sector.deleted { 0x10 } else { 0 },
+                    sector.status,
+                ]);
+                data.extend([0; 5]);
+                data.extend((sector.data.len() as u16).to_le_bytes());
+                data.extend(&sector.data);
+            }
+        }
+
+        let disk_size = data.len() as u32;
+        data[0x1c..0x20].copy_from_slice(&disk_size.to_le_bytes());
+        data
+    }
+
+    fn to_raw_2d(&self) -> Vec<u8> {
+        // Sectors that were formatted with another layout are padded or cut to fit
+        let mut data = vec![];
+        for track in &self.tracks {
+            for r in 1..=RAW_SECTORS_PER_TRACK as u8 {
+                let mut sector_data = match track.sectors.iter().find(|sector| sector.r == r) {
+                    Some(sector) => sector.data.clone(),
+                    None => vec![],
+                };
+                sector_data.resize(RAW_SECTOR_SIZE, 0);
+                data.extend(sector_data);
+            }
+        }
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if is_d88(data) {
+            Self::parse_d88(data)
+        } else if !data.is_empty() && data.len() % (RAW_SECTORS_PER_TRACK * RAW_SECTOR_SIZE) == 0 {
+            Ok(Self::parse_raw_2d(data))
+        } else {
+            Err(String::from("Unknown disk image format"))
+        }
+    }
+
+    fn parse_d88(data: &[u8]) -> Result<Self, String> {
+        /*
+         * $000: disk name, nul terminated (17 bytes)
+         * $011: reserved (9 bytes)
+         * $01a: write protect ($10 if protected)
+         * $01b: media type ($00 = 2D, $10 = 2DD, $20 = 2HD)
+         * $01c: disk size
+         * $020: track offsets (164 * 4 bytes), 0 if the track is unformatted
+         */
+        let read_u16 = |offs: usize| u16::from_le_bytes([data[offs], data[offs + 1]]);
+        let read_u32 = |offs: usize| u32::from_le_bytes(data[offs..offs + 4].try_into().unwrap());
+
+        let name_len = data[..0x11].iter().position(|&b| b == 0).unwrap_or(0x11);
+        let disk_size = read_u32(0x1c) as usize;
+
+        // Some images have a shorter track table, ending where the first track starts
+        let mut num_tracks = D88_MAX_TRACKS;
+        for i in 0..D88_MAX_TRACKS {
+            let offs = read_u32(0x20 + i * 4) as usize;
+            if offs != 0 && (offs < 0x20 || offs > disk_size) {
+                return Err(format!("Track {} has a bad offset {:x}", i, offs));
+            }
+            if offs != 0 && offs < 0x20 + num_tracks * 4 {
+                num_tracks = (offs - 0x20) / 4;
+            }
+        }
+
+        let mut tracks = vec![];
+        for i in 0..num_tracks {
+            let mut track = Track { sectors: vec![] };
+            let mut offs = read_u32(0x20 + i * 4) as usize;
+            if offs != 0 {
+                /*
+                 * Sector header
+                 * $0: C, $1: H, $2: R, $3: N
+                 * $4: number of sectors in the track
+                 * $6: density ($00 = double, $40 = single)
+                 * $7: deleted data mark ($10 if deleted)
+                 * $8: FDC status
+                 * $e: data size
+                 */
+                loop {
+                    if offs + D88_SECTOR_HEADER_SIZE > disk_size {
+                        return Err(format!("Track {} overruns the image", i));
+                    }
+                    let num_sectors = read_u16(offs + 4) as usize;
+                    if num_sectors == 0 {
+                        break;
+                    }
+                    let size = read_u16(offs + 0xe) as usize;
+                    let data_offs = offs + D88_SECTOR_HEADER_SIZE;
+                    if data_offs + size > disk_size {
+                        return Err(format!("Sector in track {} overruns the image", i));
+                    }
+                    track.sectors.push(Sector {
+                        c: data[offs],
+                        h: data[offs + 1],
+                        r: data[offs + 2],
+                        n: data[offs + 3],
+                        density: data[offs + 6],
+                        deleted: data[offs + 7] & 0x10 != 0,
+                        status: data[offs + 8],
+                        data: data[data_offs..data_offs + size].to_vec(),
+                    });
+                    offs = data_offs + size;
+                    if track.sectors.len() >= num_sectors {
+                        break;
+                    }
+                }
+            }
+            tracks.push(track);
+        }
+
+        Ok(Self {
+            name: String::from_utf8_lossy(&data[..name_len]).to_string(),
+            format: DiskFormat::D88,
+            write_protected: data[0x1a] & 0x10 != 0,
+            header_write_protected: data[0x1a] & 0x10 != 0,
+            media_type: data[0x1b],
+            tracks: tracks,
+
+            path: String::new(),
+            use_overlay: false,
+            modified: false,
+        })
+    }
+
+    fn parse_raw_2d(data: &[u8]) -> Self {
+        let track_size = RAW_SECTORS_PER_TRACK * RAW_SECTOR_SIZE;
+        let mut tracks = vec![];
+        for (i, track_data) in data.chunks(track_size).enumerate() {
+            let mut track = Track { sectors: vec![] };
+            for (j, sector_data) in track_data.chunks(RAW_SECTOR_SIZE).enumerate() {
+                track.sectors.push(Sector {
+                    c: (i / 2) as u8,
+                    h: (i % 2) as u8,
+                    r: (j + 1) as u8,
+                    n: 1,
+                    density: 0,
+                    deleted: false,
+                    status: 0,
+                    data: sector_data.to_vec(),
+                });
+            }
+            tracks.push(track);
+        }
+
+        Self {
+            name: String::new(),
+            format: DiskFormat::Raw2D,
+            write_protected: false,
+            header_write_protected: false,
+            media_type: 0x00,
+            tracks: tracks,
+
+            path: String::new(),
+            use_overlay: false,
+            modified: false,
+        }
+    }
+
+    pub fn high_density(&self) -> bool {
+        self.media_type == 0x20
+    }
+
+    pub fn track_len(&self) -> usize {
+        if self.high_density() {
+            TRACK_LEN_HD
+        } else {
+            TRACK_LEN_DD
+        }
+    }
+
+    pub fn track(&self, cylinder: u8, side: u8) -> Option<&Track> {
+        self.tracks.get(cylinder as usize * 2 + side as usize)
+    }
+
+    pub fn track_mut(&mut self, cylinder: u8, side: u8) -> Option<&mut Track> {
+        self.tracks.get_mut(cylinder as usize * 2 + side as usize)
+    }
+
+    pub fn set_track(&mut self, cylinder: u8, side: u8, track: Track) {
+        let idx = cylinder as usize * 2 + side as usize;
+        while self.tracks.len() <= idx {
+            self.tracks.push(Track { sectors: vec![] });
+        }
+        self.tracks[idx] = track;
+    }
+
+    pub fn find_sector(&self, cylinder: u8, side: u8, r: u8) -> Option<&Sector> {
+        self.track(cylinder, side)?
+            .sectors
+            .iter()
+            .find(|sector| sector.r == r)
+    }
+}
+
+fn is_d88(data: &[u8]) -> bool {
+    if data.len() < D88_HEADER_SIZE {
+        return false;
+    }
+    let disk_size = u32::from_le_bytes(data[0x1c..0x20].try_into().unwrap()) as usize;
+    let first_track = u32::from_le_bytes(data[0x20..0x24].try_into().unwrap()) as usize;
+    let media_type = data[0x1b];
+    disk_size <= data.len()
+        && disk_size >= D88_HEADER_SIZE
+        && first_track <= D88_HEADER_SIZE
+        && matches!(media_type, 0x00 | 0x10 | 0x20)
+}
@@ -1,76 +1,359 @@
+use crate::constants::CPU_CLOCK;
+use crate::disk::{Disk, DiskFormat, Track};
 use egui::Context;
+use log::error;
+
+/*
+ * Status register
+ * bit 7: not ready
+ * bit 6: write protected
+ * bit 5: head loaded (type I), deleted data mark (type II/III reads)
+ * bit 4: seek error (type I), record not found (type II/III)
+ * bit 3: CRC error
+ * bit 2: track 0 (type I), lost data (type II/III)
+ * bit 1: index pulse (type I), DRQ (type II/III)
+ * bit 0: busy
+ */
+const STATUS_NOT_READY: u8 = 0x80;
+const STATUS_WRITE_PROTECT: u8 = 0x40;
+const STATUS_HEAD_LOADED: u8 = 0x20;
+const STATUS_RECORD_TYPE: u8 = 0x20;
+const STATUS_SEEK_ERROR: u8 = 0x10;
+const STATUS_RECORD_NOT_FOUND: u8 = 0x10;
+const STATUS_CRC_ERROR: u8 = 0x08;
+const STATUS_TRACK_0: u8 = 0x04;
+const STATUS_LOST_DATA: u8 = 0x04;
+const STATUS_INDEX: u8 = 0x02;
+const STATUS_DRQ: u8 = 0x02;
+const STATUS_BUSY: u8 = 0x01;
+
+/*
+ * Type I flags
+ * bit 4: update track register (step commands)
+ * bit 3: load head
+ * bit 2: verify track
+ * bits 0-1: step rate
+ */
+const FLAG_UPDATE_TRACK: u8 = 0x10;
+const FLAG_HEAD_LOAD: u8 = 0x08;
+const FLAG_VERIFY: u8 = 0x04;
+
+/*
+ * Type II flags
+ * bit 4: multiple sectors
+ * bit 3: side to compare against
+ * bit 2: settle delay
+ * bit 1: side compare enable
+ * bit 0: write a deleted data mark
+ */
+const FLAG_MULTIPLE: u8 = 0x10;
+const FLAG_SIDE: u8 = 0x08;
+const FLAG_SETTLE: u8 = 0x04;
+const FLAG_SIDE_COMPARE: u8 = 0x02;
+const FLAG_DELETED_MARK: u8 = 0x01;
+
+/*
+ * Force interrupt conditions
+ * bit 3: immediately
+ * bit 2: on every index pulse
+ */
+const INT_IMMEDIATE: u8 = 0x08;
+const INT_INDEX: u8 = 0x04;
+
+// Timings for the MB8877 clocked at 1 MHz
+const CYCLES_PER_MS: u32 = CPU_CLOCK / 1000;
+const STEP_RATES_MS: [u32; 4] = [6, 12, 20, 30];
+const SETTLE_MS: u32 = 30;
+// Disks spin at 300 rpm, 2HD at 360 rpm
+const ROTATION_CYCLES_DD: u32 = CPU_CLOCK / 5;
+const ROTATION_CYCLES_HD: u32 = CPU_CLOCK / 6;
+const INDEX_PULSE_CYCLES: u32 = CYCLES_PER_MS * 4;
+// Record not found is flagged after this many revolutions without a matching ID
+const SEARCH_REVOLUTIONS: u32 = 5;
+const CRC_BYTES: u32 = 2;
+
+// D88 sector status codes
+const D88_ID_CRC_ERROR: u8 = 0xa0;
+const D88_DATA_CRC_ERROR: u8 = 0xb0;
+const D88_NO_ADDRESS_MARK: u8 = 0xe0;
+const D88_NO_DATA_MARK: u8 = 0xf0;
+
+const MAX_CYLINDER: u8 = 83;
+
+#[derive(Clone, Copy, PartialEq, Savefile)]
+enum FDCCommand {
+    TypeI,
+    ReadSector,
+    WriteSector,
+    ReadAddress,
+    ReadTrack,
+    WriteTrack,
+}
+
+#[derive(Clone, Copy, PartialEq, Savefile)]
+enum FDCEvent {
+    None,
+    EndTypeI,
+    SectorFound,
+    RecordNotFound,
+    NextByte,
+    TransferDone,
+}
+
+#[derive(Savefile)]
+pub struct Drive {
+    disk: Option<Disk>,
+    cylinder: u8,
+    motor_on: bool,
+}
+
+impl Drive {
+    fn new() -> Self {
+        Self {
+            disk: None,
+            cylinder: 0,
+            motor_on: false,
+        }
+    }
+
+    fn ready(&self) -> bool {
+        self.disk.is_some()
+    }
+
+    fn flush(&mut self) {
+        if let Some(disk) = self.disk.as_mut() {
+            if let Err(err) = disk.flush() {
+                error!("Unable to write disk {}: {err}", disk.path);
+            }
+        }
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui, num: usize) {
+        ui.menu_button(format!("Drive {}", num), |ui| {
+            if ui.button("Insert").clicked() {
+                let title = format!("Select floppy for drive {}", num);
+                if let Some(fname) = tinyfiledialogs::open_file_dialog(&title, "./", None) {
+                    match Disk::open(&fname) {
+                        Ok(disk) => {
+                            self.flush();
+                            self.disk = Some(disk);
+                        }
+                        Err(err) => error!("Unable to load disk {fname}: {err}"),
+                    }
+                }
+                ui.close_menu();
+            }
+            if let Some(disk) = self.disk.as_mut() {
+                ui.checkbox(&mut disk.write_protected, "Write protect");
+                ui.checkbox(&mut disk.use_overlay, "Write to overlay file")
+                    .on_hover_text("Save changes next to the image instead of overwriting it");
+                if ui.button("Eject").clicked() {
+                    self.flush();
+                    self.disk = None;
+                    ui.close_menu();
+                }
+            }
+        });
+    }
+}
 
 #[derive(Savefile)]
 pub struct FDC {
-    is_loaded: bool,
+    drives: [Drive; 4],
+    command: FDCCommand,
+    flags: u8,
+    status: u8,
+    pub track: u8,
     pub sector: u8,
+    data: u8,
+    step_in: bool,
     side1: bool,
     floppy_bay_select: u8,
-    offs_in_sector: u16,
-    pub data: u8,
-    reading: bool,
-    disk_data: Vec<u8>,
-    pub track: u8,
+    // Drive selected when the command started, it keeps using it if the selection changes
+    cmd_drive: usize,
+
+    // Neither line reaches the CPU, software polls the status register instead
+    intrq: bool,
+    int_on_index: bool,
+    rotation: u64,
+    event: FDCEvent,
+    event_cycles: i64,
+
+    buffer: Vec<u8>,
+    buffer_pos: usize,
+    transfer_len: usize,
+    data_crc_error: bool,
+    // Index in the track of the sector whose ID was found
+    sector_idx: usize,
 
     status_open: bool,
 }
 
 impl FDC {
-    pub fn new(disk_data: Vec<u8>) -> Self {
+    pub fn new(disk: Disk) -> Self {
+        let mut fdc = Self::none();
+        fdc.drives[0].disk = Some(disk);
+        fdc
+    }
+
+    pub fn none() -> Self {
         Self {
-            is_loaded: true,
+            drives: [Drive::new(), Drive::new(), Drive::new(), Drive::new()],
+            command: FDCCommand::TypeI,
+            flags: 0,
+            status: 0,
+            track: 0,
             sector: 0,
+            data: 0,
+            step_in: true,
             side1: false,
             floppy_bay_select: 0,
-            offs_in_sector: 0,
-            data: 0,
-            reading: false,
-            disk_data: disk_data,
-            track: 0,
+            cmd_drive: 0,
+
+            intrq: false,
+            int_on_index: false,
+            rotation: 0,
+            event: FDCEvent::None,
+            event_cycles: 0,
+
+            buffer: vec![],
+            buffer_pos: 0,
+            transfer_len: 0,
+            data_crc_error: false,
+            sector_idx: 0,
 
             status_open: false,
         }
     }
 
-    pub fn none() -> Self {
-        Self {
-            is_loaded: false,
-            sector: 0,
-            side1: false,
-            floppy_bay_select: 0,
-            offs_in_sector: 0,
-            data: 0,
-            reading: false,
-            disk_data: vec![],
-            track: 0,
+    pub fn insert(&mut self, drive: usize, disk: Disk) {
+        self.eject(drive);
+        self.drives[drive].disk = Some(disk);
+    }
 
-            status_open: false,
+    pub fn eject(&mut self, drive: usize) {
+        self.drives[drive].flush();
+        self.drives[drive].disk = None;
+    }
+
+    pub fn flush(&mut self) {
+        for drive in self.drives.iter_mut() {
+            drive.flush();
         }
     }
 
-    pub fn status(&mut self, side_effects: bool) -> u8 {
-        /*
-         * bit 7: clear if 'ready'
-         * bit 1: clear if 'seeking to byte'
-         * bit 0: clear once done reading sector
-         */
-        let mut ret = 2;
-        if self.reading {
-            if self.offs_in_sector == 0x100 {
-                if side_effects {
-                    self.reading = false;
-                    self.offs_in_sector = 0;
-                }
-            } else {
-                let sector = (self.sector as usize)
-                    + (if self.side1 { 0x10 } else { 0 })
-                    + (self.track as usize) * 0x20;
-                if side_effects {
-                    self.data =
-                        self.disk_data[sector * 0x100 + self.offs_in_sector as usize - 0x100];
-                    self.offs_in_sector += 1;
+    fn drive(&self) -> &Drive {
+        &self.drives[self.floppy_bay_select as usize]
+    }
+
+    fn drive_mut(&mut self) -> &mut Drive {
+        &mut self.drives[self.floppy_bay_select as usize]
+    }
+
+    fn disk(&self) -> Option<&Disk> {
+        self.drive().disk.as_ref()
+    }
+
+    fn cmd_disk(&self) -> Option<&Disk> {
+        self.drives[self.cmd_drive].disk.as_ref()
+    }
+
+    fn cmd_disk_mut(&mut self) -> Option<&mut Disk> {
+        self.drives[self.cmd_drive].disk.as_mut()
+    }
+
+    fn write_protected(&self) -> bool {
+        match self.disk() {
+            Some(disk) => disk.write_protected,
+            None => false,
+        }
+    }
+
+    fn current_track(&self) -> Option<&Track> {
+        let cylinder = self.drives[self.cmd_drive].cylinder;
+        self.cmd_disk()?.track(cylinder, self.side1 as u8)
+    }
+
+    fn rotation_cycles(&self) -> u32 {
+        match self.cmd_disk() {
+            Some(disk) if disk.high_density() => ROTATION_CYCLES_HD,
+            _ => ROTATION_CYCLES_DD,
+        }
+    }
+
+    fn byte_cycles(&self) -> u32 {
+        match self.cmd_disk() {
+            Some(disk) => self.rotation_cycles() / disk.track_len() as u32,
+            None => 1,
+        }
+    }
+
+    fn index_pulse(&self) -> bool {
+        let pos = self.rotation % self.rotation_cycles() as u64;
+        self.drive().ready() && pos < INDEX_PULSE_CYCLES as u64
+    }
+
+    // Cycles until a byte of the current track passes under the head, waiting at least `after`
+    fn cycles_until(&self, byte_offs: usize, after: u32) -> u32 {
+        let rotation = self.rotation_cycles() as u64;
+        let now = (self.rotation + after as u64) % rotation;
+        let target = (byte_offs as u64 * self.byte_cycles() as u64) % rotation;
+        after + ((target + rotation - now) % rotation) as u32
+    }
+
+    fn schedule(&mut self, event: FDCEvent, cycles: u32) {
+        self.event = event;
+        self.event_cycles += cycles as i64;
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        let was_index = self.index_pulse();
+        self.rotation += cycles as u64;
+        if self.int_on_index && !was_index && self.index_pulse() {
+            self.intrq = true;
+        }
+
+        if self.event == FDCEvent::None {
+            return;
+        }
+        self.event_cycles -= cycles as i64;
+        while self.event != FDCEvent::None && self.event_cycles <= 0 {
+            let event = self.event;
+            self.event = FDCEvent::None;
+            match event {
+                FDCEvent::EndTypeI => self.end_type_i(),
+                FDCEvent::SectorFound => self.sector_found(),
+                FDCEvent::RecordNotFound => {
+                    self.status |= STATUS_RECORD_NOT_FOUND;
+                    self.end_command();
                 }
-                ret |= 1;
+                FDCEvent::NextByte => self.next_byte(),
+                FDCEvent::TransferDone => self.transfer_done(),
+                FDCEvent::None => (),
+            }
+        }
+        if self.event == FDCEvent::None {
+            self.event_cycles = 0;
+        }
+    }
+
+    pub fn status(&mut self, side_effects: bool) -> u8 {
+        if side_effects {
+            self.intrq = false;
+        }
+        let mut ret = self.status;
+        if !self.drive().ready() {
+            ret |= STATUS_NOT_READY;
+        }
+        if self.command == FDCCommand::TypeI {
+            if self.write_protected() {
+                ret |= STATUS_WRITE_PROTECT;
+            }
+            if self.drive().cylinder == 0 {
+                ret |= STATUS_TRACK_0;
+            }
+            if self.index_pulse() {
+                ret |= STATUS_INDEX;
             }
         }
         ret
@@ -80,30 +363,415 @@ impl FDC {
         /*
          * $00 - restore
          * $10 - seek
-         * $80 - read sector start
-         * bit 3: alt sector size
-         * bit 2: wait stable
-         * bit 1: step times 2
+         * $20 - step
+         * $40 - step in
+         * $60 - step out
+         * $80 - read sector
+         * $a0 - write sector
+         * $c0 - read address
+         * $d0 - force interrupt
+         * $e0 - read track
+         * $f0 - write track
          */
-        match val & 0xf0 {
-            0x00 => {
-                // todo: restore
-                self.offs_in_sector = 0;
+        if val & 0xf0 == 0xd0 {
+            self.force_interrupt(val);
+            return;
+        }
+        if self.status & STATUS_BUSY != 0 {
+            // Only force interrupt is accepted while busy
+            return;
+        }
+
+        self.intrq = false;
+        self.command = FDCCommand::TypeI;
+        self.flags = val & 0x1f;
+        self.status = STATUS_BUSY;
+        self.buffer.clear();
+        self.buffer_pos = 0;
+        self.data_crc_error = false;
+        self.event = FDCEvent::None;
+        self.event_cycles = 0;
+        self.cmd_drive = self.floppy_bay_select as usize;
+
+        if val & 0x80 != 0 && !self.drive().ready() {
+            // Type II and III commands don't start without a ready drive
+            self.end_command();
+            return;
+        }
+
+        match val >> 4 {
+            0x0 => {
+                let steps = self.drive().cylinder as u32;
+                self.step_in = false;
+                self.drive_mut().cylinder = 0;
+                self.track = 0;
+                self.start_type_i(steps);
             }
-            0x10 => {
-                // todo: seek
+            0x1 => {
+                let steps = self.data as i16 - self.track as i16;
+                if steps != 0 {
+                    self.step_in = steps > 0;
+                }
+                let drive = self.drive_mut();
+                drive.cylinder =
+                    (drive.cylinder as i16 + steps).clamp(0, MAX_CYLINDER as i16) as u8;
                 self.track = self.data;
+                self.start_type_i(steps.unsigned_abs() as u32);
             }
-            0x80 => {
-                // read sector start
-                self.reading = true;
+            0x2 | 0x3 => self.step(self.step_in),
+            0x4 | 0x5 => self.step(true),
+            0x6 | 0x7 => self.step(false),
+            0x8 | 0x9 => {
+                self.command = FDCCommand::ReadSector;
+                self.search_sector(true);
+            }
+            0xa | 0xb => {
+                self.command = FDCCommand::WriteSector;
+                if self.write_protected() {
+                    self.status |= STATUS_WRITE_PROTECT;
+                    self.end_command();
+                    return;
+                }
+                self.search_sector(true);
+            }
+            0xc => {
+                self.command = FDCCommand::ReadAddress;
+                self.search_id();
+            }
+            0xe => {
+                self.command = FDCCommand::ReadTrack;
+                let track_len = self.disk().unwrap().track_len();
+                self.buffer = match self.current_track() {
+                    Some(track) => track.to_raw(track_len),
+                    None => vec![0x4e; track_len],
+                };
+                self.transfer_len = self.buffer.len();
+                let cycles = self.cycles_until(0, 0);
+                self.schedule(FDCEvent::NextByte, cycles);
+            }
+            0xf => {
+                self.command = FDCCommand::WriteTrack;
+                if self.write_protected() {
+                    self.status |= STATUS_WRITE_PROTECT;
+                    self.end_command();
+                    return;
+                }
+                // The first byte has to be written before the index pulse
+                self.transfer_len = self.disk().unwrap().track_len();
+                self.status |= STATUS_DRQ;
+                let cycles = self.cycles_until(0, 0);
+                self.schedule(FDCEvent::NextByte, cycles);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn step(&mut self, step_in: bool) {
+        self.step_in = step_in;
+        let update_track = self.flags & FLAG_UPDATE_TRACK != 0;
+        let drive = self.drive_mut();
+        if step_in {
+            drive.cylinder = (drive.cylinder + 1).min(MAX_CYLINDER);
+            if update_track {
+                self.track = self.track.wrapping_add(1);
+            }
+        } else {
+            drive.cylinder = drive.cylinder.saturating_sub(1);
+            if update_track {
+                self.track = self.track.wrapping_sub(1);
             }
-            _ => panic!("cmd {:02x}", val),
+        }
+        self.start_type_i(1);
+    }
+
+    fn start_type_i(&mut self, steps: u32) {
+        let mut cycles = steps * STEP_RATES_MS[(self.flags & 3) as usize] * CYCLES_PER_MS;
+        if self.flags & FLAG_VERIFY != 0 {
+            cycles += SETTLE_MS * CYCLES_PER_MS;
+        }
+        self.schedule(FDCEvent::EndTypeI, cycles);
+    }
+
+    fn end_type_i(&mut self) {
+        self.status = 0;
+        self.intrq = true;
+        if self.flags & FLAG_HEAD_LOAD != 0 {
+            self.status |= STATUS_HEAD_LOADED;
+        }
+        if self.flags & FLAG_VERIFY != 0 {
+            // An ID on the track under the head must match the track register
+            let track = self.track;
+            let found = match self.current_track() {
+                Some(t) => t
+                    .sectors
+                    .iter()
+                    .any(|sector| sector.c == track && sector.status != D88_ID_CRC_ERROR),
+                None => false,
+            };
+            if !found {
+                self.status |= STATUS_SEEK_ERROR;
+            }
+        }
+    }
+
+    fn end_command(&mut self) {
+        self.status &= !(STATUS_BUSY | STATUS_DRQ);
+        self.event = FDCEvent::None;
+        self.intrq = true;
+        self.buffer.clear();
+    }
+
+    fn find_sector(&self) -> Option<usize> {
+        let side_compare = self.flags & FLAG_SIDE_COMPARE != 0;
+        let side = (self.flags & FLAG_SIDE != 0) as u8;
+        self.current_track()?.sectors.iter().position(|sector| {
+            sector.c == self.track && sector.r == self.sector && (!side_compare || sector.h == side)
+        })
+    }
+
+    fn search_sector(&mut self, first: bool) {
+        let settle = if first && self.flags & FLAG_SETTLE != 0 {
+            SETTLE_MS * CYCLES_PER_MS
+        } else {
+            0
+        };
+        match self.find_sector() {
+            Some(idx) => {
+                let id_offs = self.current_track().unwrap().sector_offsets()[idx].0;
+                self.sector_idx = idx;
+                let cycles = self.cycles_until(id_offs, settle);
+                self.schedule(FDCEvent::SectorFound, cycles);
+            }
+            None => {
+                let cycles = settle + SEARCH_REVOLUTIONS * self.rotation_cycles();
+                self.schedule(FDCEvent::RecordNotFound, cycles);
+            }
+        }
+    }
+
+    fn search_id(&mut self) {
+        // Read address returns whichever ID comes under the head next
+        let offsets = match self.current_track() {
+            Some(track) => track.sector_offsets(),
+            None => vec![],
+        };
+        let next = offsets
+            .iter()
+            .map(|(id_offs, _)| self.cycles_until(*id_offs, 0))
+            .enumerate()
+            .min_by_key(|(_, cycles)| *cycles);
+        match next {
+            Some((idx, cycles)) => {
+                self.sector_idx = idx;
+                self.schedule(FDCEvent::SectorFound, cycles);
+            }
+            None => {
+                let cycles = SEARCH_REVOLUTIONS * self.rotation_cycles();
+                self.schedule(FDCEvent::RecordNotFound, cycles);
+            }
+        }
+    }
+
+    fn sector_found(&mut self) {
+        let idx = self.sector_idx;
+        let track = match self.current_track() {
+            Some(track) if idx < track.sectors.len() => track,
+            _ => {
+                self.status |= STATUS_RECORD_NOT_FOUND;
+                self.end_command();
+                return;
+            }
+        };
+        let (id_offs, data_offs) = track.sector_offsets()[idx];
+        let sector = &track.sectors[idx];
+        let crc = sector.id_crc();
+        let id = vec![
+            sector.c,
+            sector.h,
+            sector.r,
+            sector.n,
+            (crc >> 8) as u8,
+            crc as u8,
+        ];
+        let (status, deleted, len) = (sector.status, sector.deleted, sector.data.len());
+        let data = if self.command == FDCCommand::ReadSector {
+            sector.data.clone()
+        } else {
+            vec![]
+        };
+        let byte_cycles = self.byte_cycles();
+
+        if self.command == FDCCommand::ReadAddress {
+            // The track address ends up in the sector register
+            self.sector = id[0];
+            self.data_crc_error = status == D88_ID_CRC_ERROR;
+            self.transfer_len = id.len();
+            self.buffer = id;
+            self.schedule(FDCEvent::NextByte, byte_cycles);
+            return;
+        }
+
+        match status {
+            D88_ID_CRC_ERROR => {
+                self.status |= STATUS_CRC_ERROR | STATUS_RECORD_NOT_FOUND;
+                self.end_command();
+                return;
+            }
+            D88_NO_ADDRESS_MARK | D88_NO_DATA_MARK => {
+                self.status |= STATUS_RECORD_NOT_FOUND;
+                self.end_command();
+                return;
+            }
+            _ => (),
+        }
+        if self.command == FDCCommand::WriteSector {
+            self.buffer.clear();
+            self.transfer_len = len;
+            self.status |= STATUS_DRQ;
+        } else {
+            if deleted {
+                self.status |= STATUS_RECORD_TYPE;
+            }
+            self.data_crc_error = status == D88_DATA_CRC_ERROR;
+            self.transfer_len = len;
+            self.buffer = data;
+            self.buffer_pos = 0;
+        }
+        let data_cycles = (data_offs - id_offs) as u32 * byte_cycles;
+        self.schedule(FDCEvent::NextByte, data_cycles);
+    }
+
+    fn next_byte(&mut self) {
+        // The previous byte wasn't serviced in time
+        let lost = self.status & STATUS_DRQ != 0;
+        if lost {
+            self.status |= STATUS_LOST_DATA;
+        }
+        let byte_cycles = self.byte_cycles();
+
+        if matches!(
+            self.command,
+            FDCCommand::WriteSector | FDCCommand::WriteTrack
+        ) {
+            self.buffer.push(if lost { 0 } else { self.data });
+            if self.buffer.len() >= self.transfer_len {
+                self.status &= !STATUS_DRQ;
+                self.schedule(FDCEvent::TransferDone, CRC_BYTES * byte_cycles);
+            } else {
+                self.status |= STATUS_DRQ;
+                self.schedule(FDCEvent::NextByte, byte_cycles);
+            }
+        } else if self.buffer_pos < self.transfer_len {
+            self.data = self.buffer[self.buffer_pos];
+            self.buffer_pos += 1;
+            self.status |= STATUS_DRQ;
+            self.schedule(FDCEvent::NextByte, byte_cycles);
+        } else {
+            let tail = if self.command == FDCCommand::ReadTrack {
+                0
+            } else {
+                CRC_BYTES
+            };
+            self.status &= !STATUS_DRQ;
+            self.schedule(FDCEvent::TransferDone, tail * byte_cycles);
+        }
+    }
+
+    fn force_interrupt(&mut self, val: u8) {
+        if self.status & STATUS_BUSY != 0 {
+            self.status &= !(STATUS_BUSY | STATUS_DRQ);
+            self.event = FDCEvent::None;
+            self.buffer.clear();
+        } else {
+            self.command = FDCCommand::TypeI;
+            self.status = 0;
+        }
+        self.int_on_index = val & INT_INDEX != 0;
+        if val & INT_IMMEDIATE != 0 {
+            self.intrq = true;
+        }
+    }
+
+    fn transfer_done(&mut self) {
+        match self.command {
+            FDCCommand::ReadSector => {
+                if self.data_crc_error {
+                    self.status |= STATUS_CRC_ERROR;
+                    self.end_command();
+                } else if self.flags & FLAG_MULTIPLE != 0 {
+                    self.sector = self.sector.wrapping_add(1);
+                    self.search_sector(false);
+                } else {
+                    self.end_command();
+                }
+            }
+            FDCCommand::WriteSector => {
+                let deleted = self.flags & FLAG_DELETED_MARK != 0;
+                let (cylinder, side) = (self.drives[self.cmd_drive].cylinder, self.side1 as u8);
+                let data = std::mem::take(&mut self.buffer);
+                let idx = self.find_sector();
+                let disk = match self.cmd_disk_mut() {
+                    Some(disk) => disk,
+                    None => {
+                        // Ejected mid-command
+                        self.status |= STATUS_NOT_READY;
+                        self.end_command();
+                        return;
+                    }
+                };
+                if let Some(idx) = idx {
+                    disk.modified = true;
+                    let sector = &mut disk.track_mut(cylinder, side).unwrap().sectors[idx];
+                    sector.data = data;
+                    sector.deleted = deleted;
+                    sector.status = 0;
+                }
+
+                if self.flags & FLAG_MULTIPLE != 0 {
+                    self.sector = self.sector.wrapping_add(1);
+                    self.search_sector(false);
+                } else {
+                    self.end_command();
+                }
+            }
+            FDCCommand::ReadAddress => {
+                if self.data_crc_error {
+                    self.status |= STATUS_CRC_ERROR;
+                }
+                self.end_command();
+            }
+            FDCCommand::WriteTrack => {
+                let track = Track::from_raw(&self.buffer);
+                let (cylinder, side) = (self.drives[self.cmd_drive].cylinder, self.side1 as u8);
+                match self.cmd_disk_mut() {
+                    Some(disk) => {
+                        disk.set_track(cylinder, side, track);
+                        disk.modified = true;
+                    }
+                    None => self.status |= STATUS_NOT_READY,
+                }
+                self.end_command();
+            }
+            FDCCommand::ReadTrack | FDCCommand::TypeI => self.end_command(),
+        }
+    }
+
+    pub fn read_data(&mut self, side_effects: bool) -> u8 {
+        if side_effects && self.command != FDCCommand::TypeI {
+            self.status &= !STATUS_DRQ;
+        }
+        self.data
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.data = value;
+        if self.command != FDCCommand::TypeI {
+            self.status &= !STATUS_DRQ;
         }
     }
 
     pub fn get_sector(&self) -> u8 {
-        if self.is_loaded {
+        if self.drive().ready() {
             self.sector
         } else {
             0
@@ -112,12 +780,16 @@ impl FDC {
 
     pub fn set_floppy(&mut self, val: u8) {
         /*
-         * bit 7: set if stopping motor, clear if starting
+         * bit 7: motor on
          * bit 4: set if side 1, clear if side 0
          * bits 0-1: floppy bay selected
          */
         self.side1 = (val & 0x10) != 0;
         self.floppy_bay_select = val & 3;
+        // The motor line is shared by all drives
+        for drive in self.drives.iter_mut() {
+            drive.motor_on = val & 0x80 != 0;
+        }
     }
 
     pub fn ui(&mut self, ctx: &Context, ui: &mut egui::Ui) {
@@ -126,16 +798,68 @@ impl FDC {
                 self.status_open = true;
                 ui.close_menu();
             }
+            for (i, drive) in self.drives.iter_mut().enumerate() {
+                drive.ui(ui, i);
+            }
         });
 
+        let status = self.status(false);
         egui::Window::new("Status")
             .open(&mut self.status_open)
             .show(ctx, |ui| {
+                for (i, drive) in self.drives.iter().enumerate() {
+                    match &drive.disk {
+                        None => ui.label(format!("Drive {}: no disk inserted", i)),
+                        Some(disk) => ui.label(format!(
+                            "Drive {}: {} ({}){}",
+                            i,
+                            disk.name,
+                            match disk.format {
+                                DiskFormat::D88 => "D88",
+                                DiskFormat::Raw2D => "2D",
+                            },
+                            if disk.modified { ", modified" } else { "" }
+                        )),
+                    };
+                    ui.label(format!(
+                        "Cylinder: {:02x}, motor {}",
+                        drive.cylinder,
+                        if drive.motor_on { "on" } else { "off" }
+                    ));
+                }
+                ui.separator();
                 ui.label(format!("Floppy selected: {}", self.floppy_bay_select));
+                ui.label(format!(
+                    "Command: {}",
+                    match self.command {
+                        FDCCommand::TypeI => "Type I",
+                        FDCCommand::ReadSector => "Read sector",
+                        FDCCommand::WriteSector => "Write sector",
+                        FDCCommand::ReadAddress => "Read address",
+                        FDCCommand::ReadTrack => "Read track",
+                        FDCCommand::WriteTrack => "Write track",
+                    }
+                ));
+                ui.label(format!("Status: {:02x}", status));
+                ui.label(format!(
+                    "INTRQ: {}, DRQ: {}",
+                    self.intrq,
+                    self.command != FDCCommand::TypeI && status & STATUS_DRQ != 0
+                ));
                 ui.label(format!("Track: {:02x}", self.track));
                 ui.label(format!("Side: {}", if self.side1 { "B" } else { "A" }));
                 ui.label(format!("Sector: {:02x}", self.sector));
-                ui.label(format!("Offs in sector: {:02x}", self.offs_in_sector));
+                ui.label(format!(
+                    "Transferred: {:04x}/{:04x}",
+                    if self.command == FDCCommand::WriteSector
+                        || self.command == FDCCommand::WriteTrack
+                    {
+                        self.buffer.len()
+                    } else {
+                        self.buffer_pos
+                    },
+                    self.transfer_len
+                ));
             });
     }
 }
@@ -1,4 +1,8 @@
+use crate::audio::Audio;
 use crate::disassembler::Disassembler;
+use crate::joystick::Joystick;
+use crate::keymap::Keymap;
+use crate::timing::Timing;
 use crate::watchpoints::Watchpoints;
 use crate::{breakpoints::Breakpoints, video::VramViewers};
 use egui::{ClippedPrimitive, Context, TextureHandle, TexturesDelta};
@@ -6,6 +10,7 @@ use egui_memory_editor::MemoryEditor;
 use egui_wgpu::renderer::{Renderer, ScreenDescriptor};
 use egui_winit::winit::event_loop::EventLoopWindowTarget;
 use egui_winit::winit::window::Window;
+use log::error;
 use pixels::{wgpu, PixelsContext};
 use savefile::save_file;
 
@@ -99,6 +104,10 @@ impl Framework {
         breakpoints: &mut Breakpoints,
         watchpoints: &mut Watchpoints,
         vram_viewers: &mut VramViewers,
+        audio: &mut Audio,
+        timing: &mut Timing,
+        keymap: &mut Keymap,
+        joystick: &mut Joystick,
     ) {
         // Run the egui frame and create all paint jobs to prepare for rendering.
         let raw_input = self.egui_state.take_egui_input(window);
@@ -111,11 +120,19 @@ impl Framework {
                     let palettes = system.io.video.palettes;
                     vram_viewers.draw_pcgram(palettes, system.io.video.pcg_ram);
                     vram_viewers.draw_palettes(palettes);
+                    vram_viewers.draw_bitmaps(&system.io.video);
                     system
                         .io
                         .video
                         .ui(egui_ctx, ui, &mut self.texture_handle, vram_viewers);
                     system.io.fdc.ui(egui_ctx, ui);
+                    system.io.ctc.ui(egui_ctx, ui);
+                    system.io.cmt.ui(egui_ctx, ui);
+                    system.io.subcpu.rtc.ui(ui);
+                    keymap.ui(egui_ctx, ui);
+                    joystick.ui(ui);
+                    audio.ui(ui);
+                    timing.ui(ui);
                 });
             });
         });
@@ -291,6 +308,7 @@ impl Gui {
                     system.io.reset_pressed = true;
                 }
                 if ui.button("Save state").clicked() {
+                    system.io.subcpu.rtc.save_host_offset();
                     save_file("x1.sav", 0, system).unwrap();
                 }
                 if ui.button("Load state").clicked() {
@@ -307,12 +325,25 @@ impl Gui {
                     }
                 }
                 if ui.button("Select floppy").clicked() {
+                    // Other drives are filled from the FDC menu
                     let res = tinyfiledialogs::open_file_dialog("Select floppy", "./", None);
+                    match res {
+                        None => (),
+                        Some(fname) => match crate::Disk::open(&fname) {
+                            Ok(disk) => system.io.fdc.insert(0, disk),
+                            Err(err) => error!("Unable to load disk {fname}: {err}"),
+                        },
+                    }
+                }
+                if ui.button("Select tape").clicked() {
+                    let res = tinyfiledialogs::open_file_dialog("Select tape", "./", None);
                     match res {
                         None => (),
                         Some(fname) => {
                             let file_bytes = crate::get_file_as_byte_vec(&fname);
-                            system.io.fdc = crate::FDC::new(file_bytes);
+                            if let Err(err) = system.io.cmt.insert(file_bytes) {
+                                error!("Unable to load tape {fname}: {err}");
+                            }
                         }
                     }
                 }
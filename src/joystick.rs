@@ -0,0 +1,215 @@
+use egui_winit::winit::event::VirtualKeyCode;
+#[cfg(feature = "gamepad")]
+use log::error;
+
+/*
+Atari style joystick, read through the PSG I/O ports, port A for joystick 1 and port B for
+joystick 2. The port is active low, the JOY_* flags are active high.
+-x-- ---- trigger 2
+--x- ---- trigger 1
+---- x--- right
+---- -x-- left
+---- --x- down
+---- ---x up
+*/
+pub const JOY_UP: u8 = 0x01;
+pub const JOY_DOWN: u8 = 0x02;
+pub const JOY_LEFT: u8 = 0x04;
+pub const JOY_RIGHT: u8 = 0x08;
+pub const JOY_TRIGGER_1: u8 = 0x20;
+pub const JOY_TRIGGER_2: u8 = 0x40;
+
+pub trait JoystickBackend {
+    // JOY_* flags held on joystick 1 and 2
+    fn poll(&mut self, key_held: &dyn Fn(VirtualKeyCode) -> bool) -> [u8; 2];
+
+    // Host keys the backend reads, those don't reach the X1 keyboard
+    fn claims(&self, _: VirtualKeyCode) -> bool {
+        false
+    }
+}
+
+pub struct NullBackend;
+
+impl JoystickBackend for NullBackend {
+    fn poll(&mut self, _: &dyn Fn(VirtualKeyCode) -> bool) -> [u8; 2] {
+        [0; 2]
+    }
+}
+
+const KEYBOARD_BINDINGS: [[(VirtualKeyCode, u8); 6]; 2] = [
+    [
+        (VirtualKeyCode::Up, JOY_UP),
+        (VirtualKeyCode::Down, JOY_DOWN),
+        (VirtualKeyCode::Left, JOY_LEFT),
+        (VirtualKeyCode::Right, JOY_RIGHT),
+        (VirtualKeyCode::Z, JOY_TRIGGER_1),
+        (VirtualKeyCode::X, JOY_TRIGGER_2),
+    ],
+    [
+        (VirtualKeyCode::W, JOY_UP),
+        (VirtualKeyCode::S, JOY_DOWN),
+        (VirtualKeyCode::A, JOY_LEFT),
+        (VirtualKeyCode::D, JOY_RIGHT),
+        (VirtualKeyCode::Q, JOY_TRIGGER_1),
+        (VirtualKeyCode::E, JOY_TRIGGER_2),
+    ],
+];
+
+pub struct KeyboardBackend;
+
+impl JoystickBackend for KeyboardBackend {
+    fn poll(&mut self, key_held: &dyn Fn(VirtualKeyCode) -> bool) -> [u8; 2] {
+        let mut state = [0; 2];
+        for (port, bindings) in KEYBOARD_BINDINGS.iter().enumerate() {
+            for (keycode, flag) in bindings.iter() {
+                if key_held(*keycode) {
+                    state[port] |= flag;
+                }
+            }
+        }
+        state
+    }
+
+    fn claims(&self, keycode: VirtualKeyCode) -> bool {
+        KEYBOARD_BINDINGS
+            .iter()
+            .flatten()
+            .any(|(bound, _)| *bound == keycode)
+    }
+}
+
+// The first 2 gamepads connected, needs the `gamepad` feature
+#[cfg(feature = "gamepad")]
+pub struct GamepadBackend {
+    gilrs: gilrs::Gilrs,
+}
+
+#[cfg(feature = "gamepad")]
+const STICK_DEADZONE: f32 = 0.5;
+
+#[cfg(feature = "gamepad")]
+impl GamepadBackend {
+    pub fn new() -> Option<Self> {
+        match gilrs::Gilrs::new() {
+            Ok(gilrs) => Some(Self { gilrs: gilrs }),
+            Err(err) => {
+                error!("Unable to open gamepads: {err}");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(feature = "gamepad")]
+impl JoystickBackend for GamepadBackend {
+    fn poll(&mut self, _: &dyn Fn(VirtualKeyCode) -> bool) -> [u8; 2] {
+        use gilrs::{Axis, Button};
+
+        // The gamepad state only updates as events are taken
+        while self.gilrs.next_event().is_some() {}
+
+        let mut state = [0; 2];
+        for (port, (_, gamepad)) in self.gilrs.gamepads().take(2).enumerate() {
+            let x = gamepad.value(Axis::LeftStickX);
+            let y = gamepad.value(Axis::LeftStickY);
+            let buttons = [
+                (
+                    gamepad.is_pressed(Button::DPadUp) || y > STICK_DEADZONE,
+                    JOY_UP,
+                ),
+                (
+                    gamepad.is_pressed(Button::DPadDown) || y < -STICK_DEADZONE,
+                    JOY_DOWN,
+                ),
+                (
+                    gamepad.is_pressed(Button::DPadLeft) || x < -STICK_DEADZONE,
+                    JOY_LEFT,
+                ),
+                (
+                    gamepad.is_pressed(Button::DPadRight) || x > STICK_DEADZONE,
+                    JOY_RIGHT,
+                ),
+                (gamepad.is_pressed(Button::South), JOY_TRIGGER_1),
+                (gamepad.is_pressed(Button::East), JOY_TRIGGER_2),
+            ];
+            for (held, flag) in buttons {
+                if held {
+                    state[port] |= flag;
+                }
+            }
+        }
+        state
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum JoystickInput {
+    None,
+    Keyboard,
+    #[cfg(feature = "gamepad")]
+    Gamepad,
+}
+
+impl JoystickInput {
+    fn label(&self) -> &'static str {
+        match self {
+            JoystickInput::None => "None",
+            JoystickInput::Keyboard => "Keyboard",
+            #[cfg(feature = "gamepad")]
+            JoystickInput::Gamepad => "Gamepad",
+        }
+    }
+}
+
+pub fn open_backend(input: JoystickInput) -> Box<dyn JoystickBackend> {
+    match input {
+        JoystickInput::None => Box::new(NullBackend),
+        JoystickInput::Keyboard => Box::new(KeyboardBackend),
+        #[cfg(feature = "gamepad")]
+        JoystickInput::Gamepad => match GamepadBackend::new() {
+            Some(backend) => Box::new(backend),
+            None => Box::new(NullBackend),
+        },
+    }
+}
+
+pub struct Joystick {
+    input: JoystickInput,
+    backend: Box<dyn JoystickBackend>,
+}
+
+impl Joystick {
+    pub fn new(input: JoystickInput) -> Self {
+        Self {
+            input: input,
+            backend: open_backend(input),
+        }
+    }
+
+    pub fn poll(&mut self, key_held: &dyn Fn(VirtualKeyCode) -> bool) -> [u8; 2] {
+        self.backend.poll(key_held)
+    }
+
+    pub fn claims(&self, keycode: VirtualKeyCode) -> bool {
+        self.backend.claims(keycode)
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.menu_button("Joystick", |ui| {
+            #[allow(unused_mut)]
+            let mut inputs = vec![JoystickInput::None, JoystickInput::Keyboard];
+            #[cfg(feature = "gamepad")]
+            inputs.push(JoystickInput::Gamepad);
+            for input in inputs {
+                if ui
+                    .radio_value(&mut self.input, input, input.label())
+                    .clicked()
+                {
+                    self.backend = open_backend(input);
+                    ui.close_menu();
+                }
+            }
+        });
+    }
+}
@@ -1,187 +1,355 @@
-use egui_winit::winit::event::VirtualKeyCode;
-use winit_input_helper::WinitInputHelper;
-
-const KEY_BACKSPACE: u8 = 0x08;
-const KEY_ENTER: u8 = 0x0d;
-const KEY_RIGHT: u8 = 0x1c;
-const KEY_LEFT: u8 = 0x1d;
-const KEY_UP: u8 = 0x1e;
-const KEY_DOWN: u8 = 0x1f;
-const KEY_SPACE: u8 = 0x20;
-const KEY_QUOTES: u8 = 0x22;
-const KEY_LPAREN: u8 = 0x28;
-const KEY_RPAREN: u8 = 0x29;
-const KEY_COMMA: u8 = 0x2c;
-const KEY_0: u8 = 0x30;
-const KEY_1: u8 = 0x31;
-const KEY_2: u8 = 0x32;
-const KEY_3: u8 = 0x33;
-const KEY_4: u8 = 0x34;
-const KEY_5: u8 = 0x35;
-const KEY_6: u8 = 0x36;
-const KEY_7: u8 = 0x37;
-const KEY_8: u8 = 0x38;
-const KEY_9: u8 = 0x39;
-const KEY_COLON: u8 = 0x3a;
-const KEY_EQUALS: u8 = 0x3d;
-const KEY_A: u8 = 0x41;
-const KEY_B: u8 = 0x42;
-const KEY_C: u8 = 0x43;
-const KEY_D: u8 = 0x44;
-const KEY_E: u8 = 0x45;
-const KEY_F: u8 = 0x46;
-const KEY_H: u8 = 0x48;
-const KEY_I: u8 = 0x49;
-const KEY_K: u8 = 0x4b;
-const KEY_L: u8 = 0x4c;
-const KEY_M: u8 = 0x4d;
-const KEY_N: u8 = 0x4e;
-const KEY_O: u8 = 0x4f;
-const KEY_P: u8 = 0x50;
-const KEY_R: u8 = 0x52;
-const KEY_S: u8 = 0x53;
-const KEY_T: u8 = 0x54;
-const KEY_U: u8 = 0x55;
-const KEY_V: u8 = 0x56;
-const KEY_X: u8 = 0x58;
-const KEY_Y: u8 = 0x59;
-
-const KEYMOD_CTRL: u8 = 0x01;
-const KEYMOD_SHIFT: u8 = 0x02;
-// const KEYMOD_KANA : u8 = 0x04; // todo: graphical keyboard
-// const KEYMOD_CAPS : u8 = 0x08; // todo: graphical keyboard
-// const KEYMOD_GRAPH: u8 = 0x10; // todo: graphical keyboard
+/*
+Modifier flags as reported by the sub CPU, all of those are active low
+x--- ---- TEN: Numpad, Function key, special input key
+-x-- ---- KIN: Valid key
+--x- ---- REP: Key repeat
+---x ---- GRAPH key ON
+---- x--- CAPS lock ON
+---- -x-- KANA lock ON
+---- --x- SHIFT ON
+---- ---x CTRL ON
+*/
+pub const KEYMOD_CTRL: u8 = 0x01;
+pub const KEYMOD_SHIFT: u8 = 0x02;
+pub const KEYMOD_KANA: u8 = 0x04;
+pub const KEYMOD_CAPS: u8 = 0x08;
+pub const KEYMOD_GRAPH: u8 = 0x10;
+const KEYMOD_REP: u8 = 0x20;
+const KEYMOD_KIN: u8 = 0x40;
+const KEYMOD_TEN: u8 = 0x80;
+const KEYMOD_LOCKS: u8 = KEYMOD_KANA | KEYMOD_CAPS | KEYMOD_GRAPH;
+
+// Auto repeat, in frames
+const REPEAT_DELAY: u32 = 30;
+const REPEAT_RATE: u32 = 3;
+
+const MODIFIERS: [(&str, u8); 5] = [
+    ("CTRL", KEYMOD_CTRL),
+    ("SHIFT", KEYMOD_SHIFT),
+    ("KANA", KEYMOD_KANA),
+    ("CAPS", KEYMOD_CAPS),
+    ("GRAPH", KEYMOD_GRAPH),
+];
+
+struct X1Key {
+    name: &'static str,
+    normal: u8,
+    shift: u8,
+    // 0 if the key gives the same code as without KANA
+    kana: u8,
+    kana_shift: u8,
+    ten: bool,
+}
+
+const fn key(name: &'static str, normal: u8, shift: u8) -> X1Key {
+    X1Key {
+        name: name,
+        normal: normal,
+        shift: shift,
+        kana: 0,
+        kana_shift: 0,
+        ten: false,
+    }
+}
+
+const fn kana_key(name: &'static str, normal: u8, shift: u8, kana: u8, kana_shift: u8) -> X1Key {
+    X1Key {
+        name: name,
+        normal: normal,
+        shift: shift,
+        kana: kana,
+        kana_shift: kana_shift,
+        ten: false,
+    }
+}
+
+const fn ten_key(name: &'static str, normal: u8, shift: u8) -> X1Key {
+    X1Key {
+        name: name,
+        normal: normal,
+        shift: shift,
+        kana: 0,
+        kana_shift: 0,
+        ten: true,
+    }
+}
+
+/*
+JIS layout, kana are JIS X 0201 codes.
+The GRAPH lock is only reported in the modifier byte, the keys send their normal codes with it.
+*/
+const KEYS: [X1Key; 83] = [
+    key("ESC", 0x1b, 0x1b),
+    kana_key("1", b'1', b'!', 0xc7, 0),
+    kana_key("2", b'2', b'"', 0xcc, 0),
+    kana_key("3", b'3', b'#', 0xb1, 0xa7),
+    kana_key("4", b'4', b'$', 0xb3, 0xa9),
+    kana_key("5", b'5', b'%', 0xb4, 0xaa),
+    kana_key("6", b'6', b'&', 0xb5, 0xab),
+    kana_key("7", b'7', b'\'', 0xd4, 0xac),
+    kana_key("8", b'8', b'(', 0xd5, 0xad),
+    kana_key("9", b'9', b')', 0xd6, 0xae),
+    kana_key("0", b'0', b'0', 0xdc, 0xa6),
+    kana_key("-", b'-', b'=', 0xce, 0),
+    kana_key("^", b'^', b'~', 0xcd, 0),
+    kana_key("\u{a5}", 0x5c, b'|', 0xb0, 0),
+    ten_key("DEL", 0x08, 0x12),
+    key("TAB", 0x09, 0x09),
+    kana_key("Q", b'q', b'Q', 0xc0, 0),
+    kana_key("W", b'w', b'W', 0xc3, 0),
+    kana_key("E", b'e', b'E', 0xb2, 0xa8),
+    kana_key("R", b'r', b'R', 0xbd, 0),
+    kana_key("T", b't', b'T', 0xb6, 0),
+    kana_key("Y", b'y', b'Y', 0xdd, 0),
+    kana_key("U", b'u', b'U', 0xc5, 0),
+    kana_key("I", b'i', b'I', 0xc6, 0),
+    kana_key("O", b'o', b'O', 0xd7, 0),
+    kana_key("P", b'p', b'P', 0xbe, 0),
+    kana_key("@", b'@', b'`', 0xde, 0),
+    kana_key("[", b'[', b'{', 0xdf, 0xa2),
+    key("RETURN", 0x0d, 0x0d),
+    kana_key("A", b'a', b'A', 0xc1, 0),
+    kana_key("S", b's', b'S', 0xc4, 0),
+    kana_key("D", b'd', b'D', 0xbc, 0),
+    kana_key("F", b'f', b'F', 0xca, 0),
+    kana_key("G", b'g', b'G', 0xb7, 0),
+    kana_key("H", b'h', b'H', 0xb8, 0),
+    kana_key("J", b'j', b'J', 0xcf, 0),
+    kana_key("K", b'k', b'K', 0xc9, 0),
+    kana_key("L", b'l', b'L', 0xd8, 0),
+    kana_key(";", b';', b'+', 0xda, 0),
+    kana_key(":", b':', b'*', 0xb9, 0),
+    kana_key("]", b']', b'}', 0xd1, 0xa3),
+    kana_key("Z", b'z', b'Z', 0xc2, 0xaf),
+    kana_key("X", b'x', b'X', 0xbb, 0),
+    kana_key("C", b'c', b'C', 0xbf, 0),
+    kana_key("V", b'v', b'V', 0xcb, 0),
+    kana_key("B", b'b', b'B', 0xba, 0),
+    kana_key("N", b'n', b'N', 0xd0, 0),
+    kana_key("M", b'm', b'M', 0xd3, 0),
+    kana_key(",", b',', b'<', 0xc8, 0xa4),
+    kana_key(".", b'.', b'>', 0xd9, 0xa1),
+    kana_key("/", b'/', b'?', 0xd2, 0xa5),
+    kana_key("_", b'_', b'_', 0xdb, 0),
+    key("SPACE", b' ', b' '),
+    key("BREAK", 0x03, 0x03),
+    ten_key("INS", 0x12, 0x12),
+    ten_key("HOME", 0x0b, 0x0c),
+    ten_key("RIGHT", 0x1c, 0x1c),
+    ten_key("LEFT", 0x1d, 0x1d),
+    ten_key("UP", 0x1e, 0x1e),
+    ten_key("DOWN", 0x1f, 0x1f),
+    ten_key("F1", 0x71, 0x76),
+    ten_key("F2", 0x72, 0x77),
+    ten_key("F3", 0x73, 0x78),
+    ten_key("F4", 0x74, 0x79),
+    ten_key("F5", 0x75, 0x7a),
+    ten_key("TEN 0", b'0', b'0'),
+    ten_key("TEN 1", b'1', b'1'),
+    ten_key("TEN 2", b'2', b'2'),
+    ten_key("TEN 3", b'3', b'3'),
+    ten_key("TEN 4", b'4', b'4'),
+    ten_key("TEN 5", b'5', b'5'),
+    ten_key("TEN 6", b'6', b'6'),
+    ten_key("TEN 7", b'7', b'7'),
+    ten_key("TEN 8", b'8', b'8'),
+    ten_key("TEN 9", b'9', b'9'),
+    ten_key("TEN +", b'+', b'+'),
+    ten_key("TEN -", b'-', b'-'),
+    ten_key("TEN *", b'*', b'*'),
+    ten_key("TEN /", b'/', b'/'),
+    ten_key("TEN .", b'.', b'.'),
+    ten_key("TEN ,", b',', b','),
+    ten_key("TEN =", b'=', b'='),
+    ten_key("TEN RETURN", 0x0d, 0x0d),
+];
+
+/*
+Game keys, read all at once with sub CPU command 0xe3, 1 = held
+line 0: Q W E A D Z X C
+line 1: numpad 7 8 9 4 6 1 2 3
+line 2: ESC 1 - + * TAB SPACE RETURN
+*/
+const GAME_KEYS: [[&str; 8]; 3] = [
+    ["Q", "W", "E", "A", "D", "Z", "X", "C"],
+    [
+        "TEN 7", "TEN 8", "TEN 9", "TEN 4", "TEN 6", "TEN 1", "TEN 2", "TEN 3",
+    ],
+    [
+        "ESC", "1", "TEN -", "TEN +", "TEN *", "TAB", "SPACE", "RETURN",
+    ],
+];
+
+pub fn key_index(name: &str) -> Option<usize> {
+    KEYS.iter().position(|key| key.name == name)
+}
+
+pub fn modifier(name: &str) -> Option<u8> {
+    MODIFIERS
+        .iter()
+        .find(|(modifier, _)| *modifier == name)
+        .map(|(_, flag)| *flag)
+}
+
+// Every key that can be bound to a host key, modifiers last
+pub fn key_names() -> impl Iterator<Item = &'static str> {
+    let keys = KEYS.iter().map(|key| key.name);
+    keys.chain(MODIFIERS.iter().map(|(name, _)| *name))
+}
+
+// Keys that type a character, those come from the host's text input in symbolic mode
+pub fn is_char_key(name: &str) -> bool {
+    match key_index(name) {
+        Some(idx) => !KEYS[idx].ten && (0x20..0x7f).contains(&KEYS[idx].normal),
+        None => false,
+    }
+}
+
+// The key and shift state that type a character
+pub fn char_key(c: char) -> Option<(usize, bool)> {
+    if !(' '..='~').contains(&c) && c != '\u{a5}' {
+        return None;
+    }
+    let code = if c == '\u{a5}' { 0x5c } else { c as u8 };
+    for (idx, key) in KEYS.iter().enumerate() {
+        if key.ten {
+            continue;
+        }
+        if key.normal == code {
+            return Some((idx, false));
+        }
+        if key.shift == code {
+            return Some((idx, true));
+        }
+    }
+    None
+}
 
 #[derive(Savefile)]
 pub struct Keyboard {
+    // Code for the key being reported, 0 for none
     pub key_pressed: u8,
-    // keymods_active: u8, // todo: graphical keyboard
-    ctrl_held: bool,
-    shift_held: bool,
+    // KEYMOD_* flags, active high
+    mods_held: u8,
+    locks: u8,
+    // Lock keys pressed since the last frame
+    locks_pressed: u8,
+    repeat: bool,
+    ten: bool,
+    pub game_keys: [u8; 3],
 
-    last_press: u16,
+    // Bit n set if KEYS[n] is held
+    held: u128,
+    held_key: Option<usize>,
+    held_frames: u32,
 }
 
 impl Keyboard {
     pub fn new() -> Self {
         Self {
             key_pressed: 0x00,
-            // keymods_active: 0xff,
-            ctrl_held: false,
-            shift_held: false,
-            last_press: 0, // for check_shift
+            mods_held: 0,
+            locks: 0,
+            locks_pressed: 0,
+            repeat: false,
+            ten: false,
+            game_keys: [0; 3],
+
+            held: 0,
+            held_key: None,
+            held_frames: 0,
         }
     }
 
     pub fn check_shift(&self) -> u8 {
-        // Call this after check_keyboard_press
-        /*
-        all of those are active low
-        x--- ---- TEN: Numpad, Function key, special input key
-        -x-- ---- KIN: Valid key
-        --x- ---- REP: Key repeat
-        ---x ---- GRAPH key ON
-        ---- x--- CAPS lock ON
-        ---- -x-- KANA lock ON
-        ---- --x- SHIFT ON
-        ---- ---x CTRL ON
-        */
-        let mut ret = 0xff;
-        if self.ctrl_held {
-            ret &= 0xff - KEYMOD_CTRL;
-        }
-        if self.shift_held {
-            ret &= 0xff - KEYMOD_SHIFT;
+        let mut ret = 0xff & !(self.mods_held | self.locks);
+        if self.repeat {
+            ret &= !KEYMOD_REP;
         }
-        if self.last_press != 0 {
-            ret &= 0xff - 0x40;
+        if self.key_pressed != 0 {
+            ret &= !KEYMOD_KIN;
         }
-        if (self.last_press & 0x100) != 0 {
-            ret &= 0xff - 0x80;
+        if self.ten {
+            ret &= !KEYMOD_TEN;
         }
         ret
     }
 
-    pub fn check_press(&mut self) -> u16 {
-        let mut ret = 0x00;
-        ret |= self.key_pressed as u16;
-        self.last_press = ret;
-        ret
+    fn code(&self, key: &X1Key) -> u8 {
+        let shift = (self.mods_held & KEYMOD_SHIFT) != 0;
+        let mut code = if shift { key.shift } else { key.normal };
+        if (self.locks & KEYMOD_KANA) != 0 && key.kana != 0 {
+            code = if shift && key.kana_shift != 0 {
+                key.kana_shift
+            } else {
+                key.kana
+            };
+        } else if (self.locks & KEYMOD_CAPS) != 0 && code.is_ascii_alphabetic() {
+            code ^= 0x20;
+        }
+        if (self.mods_held & KEYMOD_CTRL) != 0 && (0x40..0x80).contains(&code) {
+            code &= 0x1f;
+        }
+        code
     }
 
-    fn set_key_pressed(&mut self, input: &WinitInputHelper, keycode: VirtualKeyCode, val: u8) {
-        if input.key_pressed(keycode) {
-            self.key_pressed = val;
-        }
+    // Once per host input update, the locks toggle on the next frame emulated
+    pub fn press_locks(&mut self, mods_pressed: u8) {
+        self.locks_pressed ^= mods_pressed & KEYMOD_LOCKS;
     }
 
-    pub fn set_btns_pressed(&mut self, input: &WinitInputHelper) {
-        self.key_pressed = 0x00;
-        self.last_press = 0x00;
-        self.shift_held = false;
-        self.ctrl_held = false;
-        self.set_key_pressed(input, VirtualKeyCode::Back, KEY_BACKSPACE);
-        self.set_key_pressed(input, VirtualKeyCode::Return, KEY_ENTER);
-        self.set_key_pressed(input, VirtualKeyCode::Right, KEY_RIGHT);
-        self.set_key_pressed(input, VirtualKeyCode::Left, KEY_LEFT);
-        self.set_key_pressed(input, VirtualKeyCode::Up, KEY_UP);
-        self.set_key_pressed(input, VirtualKeyCode::Down, KEY_DOWN);
-        self.set_key_pressed(input, VirtualKeyCode::Space, KEY_SPACE);
-
-        if input.key_pressed(VirtualKeyCode::Key2) {
-            self.shift_held = true;
-            self.key_pressed = KEY_QUOTES;
-        }
-        if input.key_pressed(VirtualKeyCode::Key9) {
-            self.shift_held = true;
-            self.key_pressed = KEY_LPAREN;
-        }
-        if input.key_pressed(VirtualKeyCode::Key0) {
-            self.shift_held = true;
-            self.key_pressed = KEY_RPAREN;
+    // Returns true when the sub CPU should send new key data
+    pub fn update(&mut self, held: u128, mods_held: u8) -> bool {
+        self.mods_held = mods_held & !KEYMOD_LOCKS;
+        self.locks ^= self.locks_pressed;
+        self.locks_pressed = 0;
+
+        for (line, names) in GAME_KEYS.iter().enumerate() {
+            self.game_keys[line] = 0;
+            for (i, name) in names.iter().enumerate() {
+                match key_index(name) {
+                    Some(idx) if (held >> idx) & 1 != 0 => self.game_keys[line] |= 0x80 >> i,
+                    _ => (),
+                }
+            }
         }
 
-        self.set_key_pressed(input, VirtualKeyCode::Comma, KEY_COMMA);
-        self.set_key_pressed(input, VirtualKeyCode::Numpad0, KEY_0);
-        self.set_key_pressed(input, VirtualKeyCode::Numpad1, KEY_1);
-        self.set_key_pressed(input, VirtualKeyCode::Numpad2, KEY_2);
-        self.set_key_pressed(input, VirtualKeyCode::Numpad3, KEY_3);
-        self.set_key_pressed(input, VirtualKeyCode::Numpad4, KEY_4);
-        self.set_key_pressed(input, VirtualKeyCode::Numpad5, KEY_5);
-        self.set_key_pressed(input, VirtualKeyCode::Numpad6, KEY_6);
-        self.set_key_pressed(input, VirtualKeyCode::Numpad7, KEY_7);
-        self.set_key_pressed(input, VirtualKeyCode::Numpad8, KEY_8);
-        self.set_key_pressed(input, VirtualKeyCode::Numpad9, KEY_9);
-        self.set_key_pressed(input, VirtualKeyCode::Colon, KEY_COLON);
-        self.set_key_pressed(input, VirtualKeyCode::Equals, KEY_EQUALS);
-
-        if input.held_shift() {
-            self.shift_held = true;
-            self.set_key_pressed(input, VirtualKeyCode::A, KEY_A);
-            self.set_key_pressed(input, VirtualKeyCode::B, KEY_B);
-            self.set_key_pressed(input, VirtualKeyCode::C, KEY_C);
-            self.set_key_pressed(input, VirtualKeyCode::D, KEY_D);
-            self.set_key_pressed(input, VirtualKeyCode::E, KEY_E);
-            self.set_key_pressed(input, VirtualKeyCode::F, KEY_F);
-            self.set_key_pressed(input, VirtualKeyCode::H, KEY_H);
-            self.set_key_pressed(input, VirtualKeyCode::I, KEY_I);
-            self.set_key_pressed(input, VirtualKeyCode::K, KEY_K);
-            self.set_key_pressed(input, VirtualKeyCode::L, KEY_L);
-            self.set_key_pressed(input, VirtualKeyCode::M, KEY_M);
-            self.set_key_pressed(input, VirtualKeyCode::N, KEY_N);
-            self.set_key_pressed(input, VirtualKeyCode::O, KEY_O);
-            self.set_key_pressed(input, VirtualKeyCode::P, KEY_P);
-            self.set_key_pressed(input, VirtualKeyCode::R, KEY_R);
-            self.set_key_pressed(input, VirtualKeyCode::S, KEY_S);
-            self.set_key_pressed(input, VirtualKeyCode::T, KEY_T);
-            self.set_key_pressed(input, VirtualKeyCode::U, KEY_U);
-            self.set_key_pressed(input, VirtualKeyCode::V, KEY_V);
-            self.set_key_pressed(input, VirtualKeyCode::X, KEY_X);
-            self.set_key_pressed(input, VirtualKeyCode::Y, KEY_Y);
+        let newly_held = held & !self.held;
+        self.held = held;
+        let mut changed = false;
+        if newly_held != 0 {
+            self.held_key = Some(newly_held.trailing_zeros() as usize);
+            self.held_frames = 0;
+            self.repeat = false;
+            changed = true;
+        } else if let Some(idx) = self.held_key {
+            if (held >> idx) & 1 != 0 {
+                self.held_frames += 1;
+                if self.held_frames >= REPEAT_DELAY
+                    && (self.held_frames - REPEAT_DELAY) % REPEAT_RATE == 0
+                {
+                    self.repeat = true;
+                    changed = true;
+                }
+            } else {
+                self.held_key = None;
+                self.repeat = false;
+                changed = true;
+            }
         }
 
-        if input.held_control() {
-            self.ctrl_held = true;
+        if changed {
+            match self.held_key {
+                Some(idx) => {
+                    self.key_pressed = self.code(&KEYS[idx]);
+                    self.ten = KEYS[idx].ten;
+                }
+                None => {
+                    self.key_pressed = 0;
+                    self.ten = false;
+                }
+            }
         }
+        changed
     }
 }
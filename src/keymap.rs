@@ -0,0 +1,600 @@
+use std::collections::VecDeque;
+use std::fs;
+
+use egui::Context;
+use egui_winit::winit::event::VirtualKeyCode;
+use log::error;
+use winit_input_helper::{TextChar, WinitInputHelper};
+
+use crate::keyboard::{
+    char_key, is_char_key, key_index, key_names, modifier, KEYMOD_CTRL, KEYMOD_SHIFT,
+};
+
+const CONFIG_PATH: &str = "keymap.cfg";
+
+// Host keys that can be bound, by their winit name
+const HOST_KEYS: [VirtualKeyCode; 120] = [
+    VirtualKeyCode::Key1,
+    VirtualKeyCode::Key2,
+    VirtualKeyCode::Key3,
+    VirtualKeyCode::Key4,
+    VirtualKeyCode::Key5,
+    VirtualKeyCode::Key6,
+    VirtualKeyCode::Key7,
+    VirtualKeyCode::Key8,
+    VirtualKeyCode::Key9,
+    VirtualKeyCode::Key0,
+    VirtualKeyCode::A,
+    VirtualKeyCode::B,
+    VirtualKeyCode::C,
+    VirtualKeyCode::D,
+    VirtualKeyCode::E,
+    VirtualKeyCode::F,
+    VirtualKeyCode::G,
+    VirtualKeyCode::H,
+    VirtualKeyCode::I,
+    VirtualKeyCode::J,
+    VirtualKeyCode::K,
+    VirtualKeyCode::L,
+    VirtualKeyCode::M,
+    VirtualKeyCode::N,
+    VirtualKeyCode::O,
+    VirtualKeyCode::P,
+    VirtualKeyCode::Q,
+    VirtualKeyCode::R,
+    VirtualKeyCode::S,
+    VirtualKeyCode::T,
+    VirtualKeyCode::U,
+    VirtualKeyCode::V,
+    VirtualKeyCode::W,
+    VirtualKeyCode::X,
+    VirtualKeyCode::Y,
+    VirtualKeyCode::Z,
+    VirtualKeyCode::Escape,
+    VirtualKeyCode::F1,
+    VirtualKeyCode::F2,
+    VirtualKeyCode::F3,
+    VirtualKeyCode::F4,
+    VirtualKeyCode::F5,
+    VirtualKeyCode::F6,
+    VirtualKeyCode::F7,
+    VirtualKeyCode::F8,
+    VirtualKeyCode::F9,
+    VirtualKeyCode::F10,
+    VirtualKeyCode::F11,
+    VirtualKeyCode::F12,
+    VirtualKeyCode::Snapshot,
+    VirtualKeyCode::Scroll,
+    VirtualKeyCode::Pause,
+    VirtualKeyCode::Insert,
+    VirtualKeyCode::Home,
+    VirtualKeyCode::Delete,
+    VirtualKeyCode::End,
+    VirtualKeyCode::PageDown,
+    VirtualKeyCode::PageUp,
+    VirtualKeyCode::Left,
+    VirtualKeyCode::Up,
+    VirtualKeyCode::Right,
+    VirtualKeyCode::Down,
+    VirtualKeyCode::Back,
+    VirtualKeyCode::Return,
+    VirtualKeyCode::Space,
+    VirtualKeyCode::Caret,
+    VirtualKeyCode::Numlock,
+    VirtualKeyCode::Numpad0,
+    VirtualKeyCode::Numpad1,
+    VirtualKeyCode::Numpad2,
+    VirtualKeyCode::Numpad3,
+    VirtualKeyCode::Numpad4,
+    VirtualKeyCode::Numpad5,
+    VirtualKeyCode::Numpad6,
+    VirtualKeyCode::Numpad7,
+    VirtualKeyCode::Numpad8,
+    VirtualKeyCode::Numpad9,
+    VirtualKeyCode::NumpadAdd,
+    VirtualKeyCode::NumpadDivide,
+    VirtualKeyCode::NumpadDecimal,
+    VirtualKeyCode::NumpadComma,
+    VirtualKeyCode::NumpadEnter,
+    VirtualKeyCode::NumpadEquals,
+    VirtualKeyCode::NumpadMultiply,
+    VirtualKeyCode::NumpadSubtract,
+    VirtualKeyCode::AbntC1,
+    VirtualKeyCode::AbntC2,
+    VirtualKeyCode::Apostrophe,
+    VirtualKeyCode::Apps,
+    VirtualKeyCode::Asterisk,
+    VirtualKeyCode::At,
+    VirtualKeyCode::Backslash,
+    VirtualKeyCode::Capital,
+    VirtualKeyCode::Colon,
+    VirtualKeyCode::Comma,
+    VirtualKeyCode::Convert,
+    VirtualKeyCode::Equals,
+    VirtualKeyCode::Grave,
+    VirtualKeyCode::Kana,
+    VirtualKeyCode::Kanji,
+    VirtualKeyCode::LAlt,
+    VirtualKeyCode::LBracket,
+    VirtualKeyCode::LControl,
+    VirtualKeyCode::LShift,
+    VirtualKeyCode::LWin,
+    VirtualKeyCode::Minus,
+    VirtualKeyCode::NoConvert,
+    VirtualKeyCode::OEM102,
+    VirtualKeyCode::Period,
+    VirtualKeyCode::Plus,
+    VirtualKeyCode::RAlt,
+    VirtualKeyCode::RBracket,
+    VirtualKeyCode::RControl,
+    VirtualKeyCode::RShift,
+    VirtualKeyCode::RWin,
+    VirtualKeyCode::Semicolon,
+    VirtualKeyCode::Slash,
+    VirtualKeyCode::Tab,
+    VirtualKeyCode::Underline,
+    VirtualKeyCode::Yen,
+];
+
+// Bindings shared by every layout
+const COMMON_BINDINGS: [(VirtualKeyCode, &str); 67] = [
+    (VirtualKeyCode::Escape, "ESC"),
+    (VirtualKeyCode::Key1, "1"),
+    (VirtualKeyCode::Key2, "2"),
+    (VirtualKeyCode::Key3, "3"),
+    (VirtualKeyCode::Key4, "4"),
+    (VirtualKeyCode::Key5, "5"),
+    (VirtualKeyCode::Key6, "6"),
+    (VirtualKeyCode::Key7, "7"),
+    (VirtualKeyCode::Key8, "8"),
+    (VirtualKeyCode::Key9, "9"),
+    (VirtualKeyCode::Key0, "0"),
+    (VirtualKeyCode::Minus, "-"),
+    (VirtualKeyCode::Back, "DEL"),
+    (VirtualKeyCode::Delete, "DEL"),
+    (VirtualKeyCode::Tab, "TAB"),
+    (VirtualKeyCode::Q, "Q"),
+    (VirtualKeyCode::W, "W"),
+    (VirtualKeyCode::E, "E"),
+    (VirtualKeyCode::R, "R"),
+    (VirtualKeyCode::T, "T"),
+    (VirtualKeyCode::Y, "Y"),
+    (VirtualKeyCode::U, "U"),
+    (VirtualKeyCode::I, "I"),
+    (VirtualKeyCode::O, "O"),
+    (VirtualKeyCode::P, "P"),
+    (VirtualKeyCode::Return, "RETURN"),
+    (VirtualKeyCode::A, "A"),
+    (VirtualKeyCode::S, "S"),
+    (VirtualKeyCode::D, "D"),
+    (VirtualKeyCode::F, "F"),
+    (VirtualKeyCode::G, "G"),
+    (VirtualKeyCode::H, "H"),
+    (VirtualKeyCode::J, "J"),
+    (VirtualKeyCode::K, "K"),
+    (VirtualKeyCode::L, "L"),
+    (VirtualKeyCode::Semicolon, ";"),
+    (VirtualKeyCode::Z, "Z"),
+    (VirtualKeyCode::X, "X"),
+    (VirtualKeyCode::C, "C"),
+    (VirtualKeyCode::V, "V"),
+    (VirtualKeyCode::B, "B"),
+    (VirtualKeyCode::N, "N"),
+    (VirtualKeyCode::M, "M"),
+    (VirtualKeyCode::Comma, ","),
+    (VirtualKeyCode::Period, "."),
+    (VirtualKeyCode::Slash, "/"),
+    (VirtualKeyCode::Space, "SPACE"),
+    (VirtualKeyCode::Pause, "BREAK"),
+    (VirtualKeyCode::Insert, "INS"),
+    (VirtualKeyCode::Home, "HOME"),
+    (VirtualKeyCode::Right, "RIGHT"),
+    (VirtualKeyCode::Left, "LEFT"),
+    (VirtualKeyCode::Up, "UP"),
+    (VirtualKeyCode::Down, "DOWN"),
+    (VirtualKeyCode::F1, "F1"),
+    (VirtualKeyCode::F2, "F2"),
+    (VirtualKeyCode::F3, "F3"),
+    (VirtualKeyCode::F4, "F4"),
+    (VirtualKeyCode::F5, "F5"),
+    (VirtualKeyCode::LControl, "CTRL"),
+    (VirtualKeyCode::RControl, "CTRL"),
+    (VirtualKeyCode::LShift, "SHIFT"),
+    (VirtualKeyCode::RShift, "SHIFT"),
+    (VirtualKeyCode::Capital, "CAPS"),
+    (VirtualKeyCode::LAlt, "GRAPH"),
+    (VirtualKeyCode::RAlt, "KANA"),
+    (VirtualKeyCode::F6, "KANA"),
+];
+
+const NUMPAD_BINDINGS: [(VirtualKeyCode, &str); 18] = [
+    (VirtualKeyCode::Numpad0, "TEN 0"),
+    (VirtualKeyCode::Numpad1, "TEN 1"),
+    (VirtualKeyCode::Numpad2, "TEN 2"),
+    (VirtualKeyCode::Numpad3, "TEN 3"),
+    (VirtualKeyCode::Numpad4, "TEN 4"),
+    (VirtualKeyCode::Numpad5, "TEN 5"),
+    (VirtualKeyCode::Numpad6, "TEN 6"),
+    (VirtualKeyCode::Numpad7, "TEN 7"),
+    (VirtualKeyCode::Numpad8, "TEN 8"),
+    (VirtualKeyCode::Numpad9, "TEN 9"),
+    (VirtualKeyCode::NumpadAdd, "TEN +"),
+    (VirtualKeyCode::NumpadSubtract, "TEN -"),
+    (VirtualKeyCode::NumpadMultiply, "TEN *"),
+    (VirtualKeyCode::NumpadDivide, "TEN /"),
+    (VirtualKeyCode::NumpadDecimal, "TEN ."),
+    (VirtualKeyCode::NumpadComma, "TEN ,"),
+    (VirtualKeyCode::NumpadEquals, "TEN ="),
+    (VirtualKeyCode::NumpadEnter, "TEN RETURN"),
+];
+
+/*
+The keys around the letters that move between layouts, placed where the JIS keys are. The host
+key codes follow the character on the key, so they differ by layout.
+*/
+const US_BINDINGS: [(VirtualKeyCode, &str); 7] = [
+    (VirtualKeyCode::Equals, "^"),
+    (VirtualKeyCode::Grave, "\u{a5}"),
+    (VirtualKeyCode::LBracket, "@"),
+    (VirtualKeyCode::RBracket, "["),
+    (VirtualKeyCode::Apostrophe, ":"),
+    (VirtualKeyCode::Backslash, "]"),
+    // No key next to the right shift
+    (VirtualKeyCode::End, "_"),
+];
+
+const UK_BINDINGS: [(VirtualKeyCode, &str); 7] = [
+    (VirtualKeyCode::Equals, "^"),
+    (VirtualKeyCode::Grave, "\u{a5}"),
+    (VirtualKeyCode::LBracket, "@"),
+    (VirtualKeyCode::RBracket, "["),
+    (VirtualKeyCode::Apostrophe, ":"),
+    (VirtualKeyCode::Backslash, "]"),
+    (VirtualKeyCode::OEM102, "_"),
+];
+
+const JIS_BINDINGS: [(VirtualKeyCode, &str); 12] = [
+    (VirtualKeyCode::Caret, "^"),
+    (VirtualKeyCode::Yen, "\u{a5}"),
+    (VirtualKeyCode::At, "@"),
+    (VirtualKeyCode::LBracket, "["),
+    (VirtualKeyCode::Colon, ":"),
+    (VirtualKeyCode::RBracket, "]"),
+    (VirtualKeyCode::Backslash, "_"),
+    (VirtualKeyCode::AbntC1, "_"),
+    (VirtualKeyCode::OEM102, "_"),
+    (VirtualKeyCode::Kana, "KANA"),
+    (VirtualKeyCode::Kanji, "GRAPH"),
+    (VirtualKeyCode::Convert, "GRAPH"),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Layout {
+    US,
+    UK,
+    JIS,
+}
+
+impl Layout {
+    fn label(&self) -> &'static str {
+        match self {
+            Layout::US => "US",
+            Layout::UK => "UK",
+            Layout::JIS => "JIS",
+        }
+    }
+
+    fn bindings(&self) -> Vec<(VirtualKeyCode, &'static str)> {
+        let layout: &[(VirtualKeyCode, &'static str)] = match self {
+            Layout::US => &US_BINDINGS,
+            Layout::UK => &UK_BINDINGS,
+            Layout::JIS => &JIS_BINDINGS,
+        };
+        let mut bindings = COMMON_BINDINGS.to_vec();
+        bindings.extend_from_slice(&NUMPAD_BINDINGS);
+        bindings.extend_from_slice(layout);
+        bindings
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KeymapMode {
+    // Host keys press the X1 key in the same place on a JIS keyboard
+    Positional,
+    // Characters typed on the host are typed on the X1, keys that don't type one are still bound
+    Symbolic,
+}
+
+fn host_key(name: &str) -> Option<VirtualKeyCode> {
+    HOST_KEYS
+        .iter()
+        .find(|keycode| format!("{:?}", keycode) == name)
+        .copied()
+}
+
+fn x1_key(name: &str) -> Option<&'static str> {
+    key_names().find(|key| *key == name)
+}
+
+pub struct Keymap {
+    pub mode: KeymapMode,
+    pub bindings: Vec<(VirtualKeyCode, &'static str)>,
+
+    // Symbolic mode, each typed key is held for a frame then released
+    typed: VecDeque<(usize, bool)>,
+    typing: Option<(usize, bool)>,
+
+    window_open: bool,
+    // X1 key waiting for a host key to be pressed
+    binding: Option<&'static str>,
+    status: String,
+}
+
+impl Keymap {
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            mode: KeymapMode::Positional,
+            bindings: layout.bindings(),
+
+            typed: VecDeque::new(),
+            typing: None,
+
+            window_open: false,
+            binding: None,
+            status: String::new(),
+        }
+    }
+
+    // The user's key bindings, or the US layout if there aren't any
+    pub fn load() -> Self {
+        let mut keymap = Self::new(Layout::US);
+        if let Ok(text) = fs::read_to_string(CONFIG_PATH) {
+            if let Err(err) = keymap.parse(&text) {
+                error!("{}: {}", CONFIG_PATH, err);
+                keymap = Self::new(Layout::US);
+            }
+        }
+        keymap
+    }
+
+    /*
+    One setting or binding per line, # starts a comment
+    mode = positional|symbolic
+    <winit key name> = <X1 key name>
+    */
+    pub fn parse(&mut self, text: &str) -> Result<(), String> {
+        let mut bindings = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (host, x1) = match line.split_once('=') {
+                Some((host, x1)) => (host.trim(), x1.trim()),
+                None => return Err(format!("line {}: expected `key = value`", i + 1)),
+            };
+            if host == "mode" {
+                self.mode = match x1 {
+                    "positional" => KeymapMode::Positional,
+                    "symbolic" => KeymapMode::Symbolic,
+                    _ => return Err(format!("line {}: unknown mode {}", i + 1, x1)),
+                };
+                continue;
+            }
+            let keycode = match host_key(host) {
+                Some(keycode) => keycode,
+                None => return Err(format!("line {}: unknown host key {}", i + 1, host)),
+            };
+            match x1_key(x1) {
+                Some(x1) => bindings.push((keycode, x1)),
+                None => return Err(format!("line {}: unknown X1 key {}", i + 1, x1)),
+            }
+        }
+        self.bindings = bindings;
+        Ok(())
+    }
+
+    pub fn to_config(&self) -> String {
+        let mut text = String::from("# x1-emu key bindings, <host key> = <X1 key>\n");
+        text += match self.mode {
+            KeymapMode::Positional => "mode = positional\n",
+            KeymapMode::Symbolic => "mode = symbolic\n",
+        };
+        for (keycode, x1) in self.bindings.iter() {
+            text += &format!("{:?} = {}\n", keycode, x1);
+        }
+        text
+    }
+
+    fn save(&mut self) {
+        self.status = match fs::write(CONFIG_PATH, self.to_config()) {
+            Ok(()) => format!("Saved to {}", CONFIG_PATH),
+            Err(err) => format!("Couldn't save {}: {}", CONFIG_PATH, err),
+        };
+    }
+
+    fn reload(&mut self) {
+        self.status = match fs::read_to_string(CONFIG_PATH) {
+            Ok(text) => match self.parse(&text) {
+                Ok(()) => format!("Loaded {}", CONFIG_PATH),
+                Err(err) => err,
+            },
+            Err(err) => format!("Couldn't load {}: {}", CONFIG_PATH, err),
+        };
+    }
+
+    // Queues characters typed on the host, for symbolic mode
+    pub fn type_char(&mut self, c: char, ctrl: bool) {
+        // Ctrl+letter comes through as a control character
+        let c = match c as u32 {
+            0x01..=0x1a if ctrl => (c as u8 + 0x60) as char,
+            _ => c,
+        };
+        if let Some(key) = char_key(c) {
+            self.typed.push_back(key);
+        }
+    }
+
+    /*
+    Called once per input update, before any frames are emulated. Key presses and typed text are
+    only reported for the update they happen in, while scan runs for 0 or more frames.
+    Host keys taken by the joystick are left out. Returns the modifiers pressed.
+    */
+    pub fn update(
+        &mut self,
+        input: &WinitInputHelper,
+        claimed: &dyn Fn(VirtualKeyCode) -> bool,
+    ) -> u8 {
+        if let Some(x1) = self.binding {
+            if let Some(keycode) = HOST_KEYS
+                .iter()
+                .find(|keycode| input.key_pressed(**keycode))
+            {
+                self.bindings.retain(|(bound, _)| bound != keycode);
+                self.bindings.push((*keycode, x1));
+                self.binding = None;
+            }
+            return 0;
+        }
+
+        let mut mods_pressed = 0;
+        for (keycode, name) in self.bindings.iter() {
+            if let Some(flag) = modifier(name) {
+                if input.key_pressed(*keycode) && !claimed(*keycode) {
+                    mods_pressed |= flag;
+                }
+            }
+        }
+
+        // Text can't be traced back to its key, so none is taken while a claimed key goes down
+        let claimed_pressed = HOST_KEYS
+            .iter()
+            .any(|keycode| claimed(*keycode) && input.key_pressed(*keycode));
+        if self.mode == KeymapMode::Symbolic && !claimed_pressed {
+            let ctrl = self.bindings.iter().any(|(keycode, name)| {
+                modifier(name) == Some(KEYMOD_CTRL) && input.key_held(*keycode)
+            });
+            for text in input.text() {
+                if let TextChar::Char(c) = text {
+                    self.type_char(c, ctrl);
+                }
+            }
+        }
+        mods_pressed
+    }
+
+    /*
+    Returns the X1 keys held as a bitmask of keyboard::KEYS and the modifiers held, from the host
+    keys key_held reports
+    */
+    pub fn scan(&mut self, key_held: &dyn Fn(VirtualKeyCode) -> bool) -> (u128, u8) {
+        if self.binding.is_some() {
+            return (0, 0);
+        }
+
+        let symbolic = self.mode == KeymapMode::Symbolic;
+        let mut held = 0;
+        let mut mods_held = 0;
+        for (keycode, name) in self.bindings.iter() {
+            if !key_held(*keycode) || (symbolic && is_char_key(name)) {
+                continue;
+            }
+            if let Some(flag) = modifier(name) {
+                mods_held |= flag;
+            } else if let Some(idx) = key_index(name) {
+                held |= 1 << idx;
+            }
+        }
+
+        // Characters queued by update
+        if symbolic {
+            let (typed_held, shift) = self.next_typed();
+            if typed_held != 0 {
+                held |= typed_held;
+                mods_held &= !KEYMOD_SHIFT;
+                if shift {
+                    mods_held |= KEYMOD_SHIFT;
+                }
+            }
+        }
+        (held, mods_held)
+    }
+
+    // Typed keys are released for a frame in between, so the same key can be typed twice
+    pub fn next_typed(&mut self) -> (u128, bool) {
+        if self.typing.take().is_some() {
+            return (0, false);
+        }
+        match self.typed.pop_front() {
+            Some((idx, shift)) => {
+                self.typing = Some((idx, shift));
+                (1 << idx, shift)
+            }
+            None => (0, false),
+        }
+    }
+
+    pub fn ui(&mut self, ctx: &Context, ui: &mut egui::Ui) {
+        ui.menu_button("Keyboard", |ui| {
+            if ui.button("Key Mapping").clicked() {
+                self.window_open = true;
+                ui.close_menu();
+            }
+        });
+
+        let mut open = self.window_open;
+        egui::Window::new("Key Mapping")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Preset:");
+                    for layout in [Layout::US, Layout::UK, Layout::JIS] {
+                        if ui.button(layout.label()).clicked() {
+                            self.bindings = layout.bindings();
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.mode, KeymapMode::Positional, "Positional");
+                    ui.radio_value(&mut self.mode, KeymapMode::Symbolic, "Symbolic");
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Save").clicked() {
+                        self.save();
+                    }
+                    if ui.button("Reload").clicked() {
+                        self.reload();
+                    }
+                    ui.label(&self.status);
+                });
+                ui.separator();
+
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    egui::Grid::new("keymap_grid").striped(true).show(ui, |ui| {
+                        for x1 in key_names() {
+                            ui.label(x1);
+                            let host: Vec<String> = self
+                                .bindings
+                                .iter()
+                                .filter(|(_, name)| *name == x1)
+                                .map(|(keycode, _)| format!("{:?}", keycode))
+                                .collect();
+                            ui.label(host.join(", "));
+                            if self.binding == Some(x1) {
+                                if ui.button("Press a key...").clicked() {
+                                    self.binding = None;
+                                }
+                            } else if ui.button("Bind").clicked() {
+                                self.binding = Some(x1);
+                            }
+                            if ui.button("Clear").clicked() {
+                                self.bindings.retain(|(_, name)| *name != x1);
+                            }
+                            ui.end_row();
+                        }
+                    });
+                });
+            });
+        self.window_open = open;
+    }
+}
@@ -1,10 +1,17 @@
+use crate::audio::{open_backend, Audio};
+use crate::ay8910::AY8910;
 use crate::cart::Cart;
+use crate::cmt::CMT;
+use crate::ctc::CTC;
+use crate::disk::Disk;
 use crate::fdc::FDC;
 use crate::gui::Framework;
 use crate::i8255::I8255;
-use crate::keyboard::Keyboard;
-use crate::rtc::RTC;
-use crate::video::{Video, VramViewers};
+use crate::joystick::{Joystick, JoystickInput};
+use crate::keymap::Keymap;
+use crate::subcpu::SubCPU;
+use crate::timing::Timing;
+use crate::video::{double_height_font, Video, VramViewers};
 use crate::z80::{Z80, Z80IO};
 
 use egui_winit::winit::{
@@ -13,14 +20,14 @@ use egui_winit::winit::{
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
-use log::error;
+use log::{error, warn};
 use pixels::{Error, Pixels, SurfaceTexture};
 use savefile::load_file;
 use std::fs::{metadata, File};
 use std::io::Read;
 use winit_input_helper::WinitInputHelper;
 
-use crate::constants::{CPU_CLOCK, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::constants::CPU_CLOCK;
 
 use crate::breakpoints::Breakpoints;
 use crate::disassembler::Disassembler;
@@ -29,16 +36,25 @@ use crate::watchpoints::Watchpoints;
 #[macro_use]
 extern crate savefile_derive;
 
+mod audio;
+mod ay8910;
 mod breakpoints;
 mod cart;
+mod cmt;
 mod constants;
+mod ctc;
 mod disassembler;
+mod disk;
 mod fdc;
 mod gui;
 mod i8255;
+mod joystick;
 mod keyboard;
+mod keymap;
 mod rtc;
+mod subcpu;
 mod tests;
+mod timing;
 mod video;
 mod watchpoints;
 mod z80;
@@ -52,6 +68,14 @@ pub struct System {
     pub load_state_clicked: bool,
 }
 
+// Device whose vector the CPU was last handed, that's the one an acknowledge goes to
+#[derive(Clone, Copy, PartialEq, Savefile)]
+enum IrqDevice {
+    None,
+    Key,
+    CTC,
+}
+
 #[derive(Savefile)]
 pub struct IO {
     mem: [u8; 0x10000],
@@ -62,17 +86,11 @@ pub struct IO {
     i8255: I8255,
     fdc: FDC,
     cart: Cart,
-    rtc: RTC,
-    sub_cmd: u8,
-    sub_cmd_len: u8,
-    sub_vals: [u8; 8],
-    sub_obf: u8,
-    key_i: usize,
-    sub_val_ptr: usize,
-    key_irq_vector: u8,
-
-    keyboard: Keyboard,
-    last_key_press: u8,
+    psg: AY8910,
+    ctc: CTC,
+    cmt: CMT,
+    subcpu: SubCPU,
+    irq_device: IrqDevice,
 
     last_addr: u16,
     last_is_read: bool,
@@ -84,30 +102,21 @@ pub struct IO {
 }
 
 impl IO {
-    fn new(ipl: Vec<u8>, fnt: Vec<u8>) -> Self {
+    fn new(ipl: Vec<u8>, ank: Vec<u8>, fnt: Vec<u8>) -> Self {
         let mut io = Self {
             mem: [0; 0x10000],
             ipl_loaded: true,
             ipl: [0; 0x1000],
             io_bank: false,
-            video: Video::new(
-                // ank,
-                fnt,
-            ),
+            video: Video::new(ank, fnt),
             i8255: I8255::new(),
             fdc: FDC::none(),
             cart: Cart::none(),
-            rtc: RTC::new(),
-            sub_cmd: 0,
-            sub_cmd_len: 0,
-            sub_vals: [0; 8],
-            sub_obf: 0,
-            key_i: 0,
-            sub_val_ptr: 0,
-            key_irq_vector: 0,
-
-            keyboard: Keyboard::new(),
-            last_key_press: 0,
+            psg: AY8910::new(),
+            ctc: CTC::new(),
+            cmt: CMT::new(),
+            subcpu: SubCPU::new(),
+            irq_device: IrqDevice::None,
 
             last_addr: 0xffff,
             last_is_mem: true,
@@ -123,6 +132,73 @@ impl IO {
 
         io
     }
+
+    fn add_cycles(&mut self, cycles: u32) {
+        self.video.tick(cycles);
+        self.psg.tick(cycles);
+        self.ctc.tick(cycles);
+        self.cmt.tick(cycles);
+        self.fdc.tick(cycles);
+        self.subcpu.tick(cycles);
+    }
+
+    fn port_c_written(&mut self, prev_portc: u8) {
+        if (self.i8255.port_c & 0x20) == 0 && (prev_portc & 0x20) != 0 {
+            self.io_bank = true;
+        }
+        self.cmt.set_output((self.i8255.port_c & 1) != 0);
+        self.video.hres_320 = (self.i8255.port_c & 0x40) != 0;
+        self.video.smooth_scroll = (self.i8255.port_c & 0x10) != 0;
+    }
+
+    fn set_joysticks(&mut self, joysticks: [u8; 2]) {
+        self.psg.port_a_in = !joysticks[0];
+        self.psg.port_b_in = !joysticks[1];
+        self.subcpu.joystick = joysticks[0];
+    }
+
+    fn update_irqs(&mut self, cpu: &mut Z80) {
+        /*
+         * Daisy chain, highest priority first: sub CPU key IRQ, then CTC channels 0-3.
+         * A device in service blocks every device after it until its RETI.
+         */
+        if cpu.irq_acked {
+            cpu.irq_acked = false;
+            // The device stops requesting once it's in service
+            cpu.irq_req = false;
+            match self.irq_device {
+                IrqDevice::Key => {
+                    self.subcpu.key_irq_pending = false;
+                    self.subcpu.key_irq_in_service = true;
+                }
+                IrqDevice::CTC => self.ctc.irq_ack(),
+                IrqDevice::None => (),
+            }
+        }
+        if cpu.reti_executed {
+            cpu.reti_executed = false;
+            if self.subcpu.key_irq_in_service {
+                self.subcpu.key_irq_in_service = false;
+            } else {
+                self.ctc.irq_reti();
+            }
+        }
+
+        self.irq_device = IrqDevice::None;
+        if self.subcpu.key_irq_in_service {
+            cpu.irq_req = false;
+            return;
+        }
+        if self.subcpu.key_irq_pending {
+            self.irq_device = IrqDevice::Key;
+            cpu.assert_irq(self.subcpu.key_irq_vector);
+        } else if let Some(vector) = self.ctc.irq_vector() {
+            self.irq_device = IrqDevice::CTC;
+            cpu.assert_irq(vector);
+        } else {
+            cpu.irq_req = false;
+        }
+    }
 }
 
 impl Z80IO for IO {
@@ -158,48 +234,20 @@ impl Z80IO for IO {
         self.last_is_mem = false;
 
         if self.io_bank {
-            // todo: get extra gfx bitmap ram value
+            // Any read ends simultaneous access mode
             if side_effects {
                 self.io_bank = false;
             }
-            0
+            self.video.ex_gfxram_r(addr)
         } else {
             match addr {
                 0x0000 => 0, // todo: Sofia and Brain Breaker need this?
                 0x0e03 => self.cart.read_byte(),
                 0x0ff8 => self.fdc.status(side_effects),
+                0x0ff9 => self.fdc.track,
                 0x0ffa => self.fdc.get_sector(),
-                0x0ffb => self.fdc.data,
-                0x1900..=0x19ff => {
-                    if self.sub_obf != 0 {
-                        let ret = self.sub_vals[self.key_i];
-                        if !side_effects {
-                            return ret;
-                        }
-                        self.key_i += 1;
-                        if self.key_i >= 2 {
-                            self.key_i = 0;
-                        }
-
-                        ret
-                    } else {
-                        let ret = self.sub_vals[self.sub_val_ptr];
-                        if !side_effects {
-                            return ret;
-                        }
-                        self.sub_cmd_len -= 1;
-                        match self.sub_cmd_len {
-                            0 => self.sub_obf = 0x20,
-                            _ => self.sub_obf = 0x00,
-                        }
-                        self.sub_val_ptr += 1;
-                        if self.sub_cmd_len <= 0 {
-                            self.sub_val_ptr = 0;
-                        }
-
-                        ret
-                    }
-                }
+                0x0ffb => self.fdc.read_data(side_effects),
+                0x1900..=0x19ff => self.subcpu.read(side_effects),
                 0x1a01 => {
                     /*
                     x--- ---- "v disp"
@@ -211,9 +259,8 @@ impl Z80IO for IO {
                     ---- --x- "cmt read"
                     ---- ---x "cmt test" (active low) <- actually this is "Sub CPU detected BREAK"
                     */
-                    let tile_height = self.video.hd6845s.max_ras_addr as u16 + 1;
-                    let vblank_line = self.video.hd6845s.vert_disp as u16 * tile_height;
-                    let vsync_line = self.video.hd6845s.vert_sync_pos as u16 * tile_height;
+                    let vblank_line = self.video.hd6845s.visible_lines();
+                    let vsync_line = self.video.hd6845s.vsync_line();
                     let m_vdisp = if self.video.vpos() < vblank_line {
                         0x80
                     } else {
@@ -222,19 +269,17 @@ impl Z80IO for IO {
                     let m_vsync = if self.video.vpos() < vsync_line { 0 } else { 4 };
                     let m_ram_bank = 0;
 
-                    let res = m_ram_bank | self.sub_obf | m_vsync | m_vdisp;
+                    let mut res = m_ram_bank | self.subcpu.status() | m_vsync | m_vdisp;
 
-                    // if(m_cassette->input() > 0.03)
-                    //     res |= 0x02;
+                    if self.cmt.read_bit() {
+                        res |= 0x02;
+                    }
 
                     // CMT test bit is set low when the CMT Stop command is issued, and becomes
                     // high again when this bit is read.
-                    // res |= 0x01;
-                    // if(m_cmt_test != 0)
-                    // {
-                    //     m_cmt_test = 0;
-                    //     res &= ~0x01;
-                    // }
+                    if self.cmt.test_bit(side_effects) {
+                        res |= 0x01;
+                    }
 
                     res
                 }
@@ -248,11 +293,10 @@ impl Z80IO for IO {
                     */
                     self.i8255.port_c
                 }
-                0x1b00..=0x1bff => {
-                    // ay sound
-                    // println!("Read from port 1b00");
-                    0
-                }
+                0x1400..=0x17ff => self.video.pcg_r((addr & 0x300) >> 8),
+                0x1b00..=0x1bff => self.psg.read_data(),
+                0x1fa0..=0x1fa3 => self.ctc.read((addr & 3) as usize),
+                0x1fa8..=0x1fab => self.ctc.read((addr & 3) as usize),
                 0x1ff0 => {
                     // todo: is for x1 turbo
                     0xff
@@ -278,7 +322,7 @@ impl Z80IO for IO {
         }
 
         if self.io_bank {
-            // todo: extra gfx bitmap ram
+            self.video.ex_gfxram_w(addr, value);
         } else {
             match addr {
                 0x0e00 => self.cart.set_high(value),
@@ -287,7 +331,7 @@ impl Z80IO for IO {
                 0x0ff8 => self.fdc.cmd(value),
                 0x0ff9 => self.fdc.track = value,
                 0x0ffa => self.fdc.sector = value,
-                0x0ffb => self.fdc.data = value,
+                0x0ffb => self.fdc.write_data(value),
                 0x0ffc => self.fdc.set_floppy(value),
                 0x1000..=0x10ff => self.video.set_blue(value),
                 0x1100..=0x11ff => self.video.set_red(value),
@@ -296,118 +340,25 @@ impl Z80IO for IO {
                 0x1400..=0x17ff => self.video.pcg_w((addr & 0x300) >> 8, value),
                 0x1800 => self.video.hd6845s.addr = value & 0x1f,
                 0x1801 => self.video.hd6845s.set_addr(value),
-                0x1900..=0x19ff => {
-                    let mut data = value;
-                    if self.sub_cmd == 0xe4 {
-                        self.key_irq_vector = value;
-                        data = 0;
-                    }
-                    if self.sub_cmd == 0xe7 {
-                        println!("Setting TV ctrl: {:02x}", data);
-                    }
-                    if self.sub_cmd == 0xe9 {
-                        // todo: CMT command
-                        data = 0;
-                    }
-                    if (data & 0xf0) == 0xd0 {
-                        // todo: TV-related
-                        self.sub_vals[0] = 0;
-                        self.sub_vals[1] = 0;
-                        self.sub_vals[2] = 0;
-                        self.sub_vals[3] = 0;
-                        self.sub_vals[4] = 0;
-                        self.sub_vals[5] = 0;
-                        self.sub_cmd_len = 6;
-                    }
-                    match data {
-                        0xe3 => {
-                            panic!("Implement sub cmd {:x} {:x}", self.sub_cmd, data);
-                        }
-                        0xe4 => {
-                            // Key IRQ vector set above
-                        }
-                        0xe5 => {
-                            panic!("Implement sub cmd {:x} {:x}", self.sub_cmd, data);
-                        }
-                        0xe6 => {
-                            self.sub_vals[1] = self.keyboard.check_press() as u8;
-                            self.sub_vals[0] = self.keyboard.check_shift();
-                            self.sub_cmd_len = 2;
-                        }
-                        0xe7 => {
-                            // todo: unknown TV ctrl
-                        }
-                        0xe8 => {
-                            // todo: TV ctrl read-out
-                            self.sub_vals[0] = self.sub_cmd;
-                            self.sub_cmd_len = 1;
-                        }
-                        0xe9 => {
-                            // todo: CMT ctrl
-                        }
-                        0xea => {
-                            panic!("Implement sub cmd {:x} {:x}", self.sub_cmd, data);
-                        }
-                        0xeb => {
-                            // todo: CMT tape status
-                            self.sub_vals[0] = 5;
-                            self.sub_cmd_len = 1;
-                        }
-                        0xec => {
-                            panic!("Implement sub cmd {:x} {:x}", self.sub_cmd, data);
-                        }
-                        0xed => {
-                            self.sub_vals[0] = self.rtc.day;
-                            self.sub_vals[1] = (self.rtc.month << 4) | (self.rtc.weekday & 0xf);
-                            self.sub_vals[2] = self.rtc.year;
-                            self.sub_cmd_len = 3;
-                        }
-                        0xee => {
-                            panic!("Implement sub cmd {:x} {:x}", self.sub_cmd, data);
-                        }
-                        0xef => {
-                            self.sub_vals[0] = self.rtc.hour;
-                            self.sub_vals[1] = self.rtc.minute;
-                            self.sub_vals[2] = self.rtc.second;
-                            self.sub_cmd_len = 3;
-                        }
-                        _ => (),
-                    }
-                    self.sub_cmd = data;
-
-                    match self.sub_cmd_len {
-                        0 => self.sub_obf = 0x20,
-                        _ => self.sub_obf = 0x00,
-                    }
-                }
+                0x1900..=0x19ff => self.subcpu.write(value, &mut self.cmt),
                 0x1a02 => {
                     let prev_portc = self.i8255.port_c;
-                    self.i8255.port_c &= 0xdf;
-                    self.i8255.port_c |= value & 0x20;
-                    if (self.i8255.port_c & 0x20) == 0 && (prev_portc & 0x20) != 0 {
-                        self.io_bank = true;
-                    }
+                    self.i8255.port_c &= 0x8e;
+                    self.i8255.port_c |= value & 0x71;
+                    self.port_c_written(prev_portc);
                 }
                 0x1a03 => {
                     let prev_portc = self.i8255.port_c;
                     self.i8255.set_ctrl(value);
-                    if (self.i8255.port_c & 0x20) == 0 && (prev_portc & 0x20) != 0 {
-                        self.io_bank = true;
-                    }
-                }
-                0x1b00..=0x1bff => {
-                    // ay sound
-                    // println!("Write from port 1b00 val {:x}", value);
-                }
-                0x1c00..=0x1cff => {
-                    // ay sound
-                    // println!("Write from port 1c00 val {:x}", value);
+                    self.port_c_written(prev_portc);
                 }
+                0x1b00..=0x1bff => self.psg.write_data(value),
+                0x1c00..=0x1cff => self.psg.set_addr(value),
                 0x1d00..=0x1dff => self.ipl_loaded = true,
                 0x1e00 => self.ipl_loaded = false,
-                0x1fd0 => {
-                    // todo: is x1 turbo
-                }
+                0x1fa0..=0x1fa3 => self.ctc.write((addr & 3) as usize, value),
+                0x1fa8..=0x1fab => self.ctc.write((addr & 3) as usize, value),
+                0x1fd0 => self.video.set_scrn(value),
                 0x2000..=0x27ff => self.video.avram[addr as usize - 0x2000] = value,
                 0x2800..=0x2fff => self.video.avram[addr as usize - 0x2800] = value,
                 0x3000..=0x37ff => self.video.tvram[addr as usize - 0x3000] = value,
@@ -430,12 +381,34 @@ fn get_file_as_byte_vec(filename: &String) -> Vec<u8> {
     buffer
 }
 
+// Held host keys go to either the joystick or the X1 keyboard, not both
+fn scan_inputs(
+    keymap: &mut Keymap,
+    joystick: &mut Joystick,
+    key_held: &dyn Fn(VirtualKeyCode) -> bool,
+) -> (u128, u8, [u8; 2]) {
+    let (held, mods_held) = keymap.scan(&|keycode| key_held(keycode) && !joystick.claims(keycode));
+    (held, mods_held, joystick.poll(key_held))
+}
+
 fn get_new_io() -> IO {
     let ipl = get_file_as_byte_vec(&String::from("res/ipl.x1"));
-    // let ank = get_file_as_byte_vec(&String::from("res/ank.fnt")); // 8x16
     let fnt = get_file_as_byte_vec(&String::from("res/fnt0808.x1")); // 8x8
+    let ank = match std::fs::read("res/ank.fnt") {
+        Ok(ank) => ank, // 8x16
+        Err(err) => {
+            warn!("Unable to load res/ank.fnt ({err}), using the 8x8 font doubled in height");
+            double_height_font(&fnt)
+        }
+    };
 
-    IO::new(ipl, fnt)
+    let mut io = IO::new(ipl, ank, fnt);
+    // `--host-time` starts the clock from the host's local time (UTC outside unix) instead of
+    // 1980/01/01
+    if std::env::args().any(|arg| arg == "--host-time") {
+        io.subcpu.rtc.sync_host();
+    }
+    io
 }
 
 fn main() -> Result<(), Error> {
@@ -452,17 +425,25 @@ fn main() -> Result<(), Error> {
     let mut vram_viewers = VramViewers::new(&system.io.video);
 
     env_logger::init();
+
+    // `--wav <file>` records audio to a file instead of playing it, eg for machines without a sound card
+    let wav_path = std::env::args().skip_while(|arg| arg != "--wav").nth(1);
+    let mut audio = Audio::new(open_backend(wav_path));
+    let mut timing = Timing::new();
+    let mut keymap = Keymap::load();
+    // Keyboard emulation takes keys away from the X1 keyboard, so it's opted into from the menu
+    let mut joystick = Joystick::new(JoystickInput::None);
+
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
 
+    // The video output changes size with the CRTC set up, 15 kHz modes are line doubled
+    let mut buffer_size = system.io.video.frame_size();
     let window = {
-        let xscale: f64 = 2.5;
-        let yscale = xscale * 2.0;
-        let size = LogicalSize::new(DISPLAY_WIDTH as f64, DISPLAY_HEIGHT as f64);
-        let scaled_size = LogicalSize::new(
-            DISPLAY_WIDTH as f64 * xscale,
-            DISPLAY_HEIGHT as f64 * yscale,
-        );
+        let scale: f64 = 2.5;
+        let (width, height) = (buffer_size.0 as f64, buffer_size.1 as f64);
+        let size = LogicalSize::new(width, height);
+        let scaled_size = LogicalSize::new(width * scale, height * scale);
         WindowBuilder::new()
             .with_title("Sharp X1 Emulator")
             .with_inner_size(scaled_size)
@@ -474,7 +455,7 @@ fn main() -> Result<(), Error> {
     let mut pixels = {
         let window_size = window.inner_size();
         let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
-        Pixels::new(DISPLAY_WIDTH, DISPLAY_HEIGHT, surface_texture)?
+        Pixels::new(buffer_size.0, buffer_size.1, surface_texture)?
     };
 
     let window_size = window.inner_size();
@@ -498,16 +479,18 @@ fn main() -> Result<(), Error> {
         // It returns `true` when it is time to update our game state and request a redraw.
         if input.update(&event) {
             // Close events
-            if input.key_pressed(VirtualKeyCode::Escape)
-                || input.close_requested()
-                || input.destroyed()
-            {
+            // Escape is the X1's ESC key
+            if input.close_requested() || input.destroyed() {
+                system.io.fdc.flush();
                 *control_flow = ControlFlow::Exit;
                 return;
             }
 
             if system.load_state_clicked {
+                system.io.fdc.flush();
                 system = load_file("x1.sav", 0).unwrap();
+                system.io.subcpu.rtc.resync();
+                system.io.video.restore_frames();
             }
 
             if system.io.pause_pressed {
@@ -523,7 +506,8 @@ fn main() -> Result<(), Error> {
                     system.backup_cpu.step(&mut system.io);
                     let added = system.cpu.step(&mut system.io);
                     cyc += added;
-                    system.io.video.cycles += added;
+                    system.io.add_cycles(added);
+                    system.io.update_irqs(&mut system.cpu);
                 }
             }
 
@@ -533,6 +517,7 @@ fn main() -> Result<(), Error> {
                 system.cpu = Z80::new(true);
                 system.backup_cpu.reset();
                 system.cpu.reset();
+                system.io.fdc.flush();
                 system.io = get_new_io();
             }
 
@@ -551,53 +536,77 @@ fn main() -> Result<(), Error> {
                 framework.resize(size.width, size.height);
             }
 
-            while !system.io.paused && cyc < CPU_CLOCK / 60 {
-                system.backup_cpu.step(&mut system.io);
+            let mods_pressed = keymap.update(&input, &|keycode| joystick.claims(keycode));
+            system.io.subcpu.keyboard.press_locks(mods_pressed);
 
-                system.io.paused = watchpoints.check(
-                    system.io.last_addr,
-                    system.io.last_is_read,
-                    system.io.last_is_mem,
-                );
+            // Frames due since the last update, all but the last one are skipped when behind
+            let frames = timing.frames_due(system.io.paused);
+            let mut emulated = 0;
+            for i in 0..frames {
                 if system.io.paused {
-                    system.backup_cpu = system.cpu.clone();
-                    system.backup_cpu.side_effects = false;
                     break;
                 }
+                // A field can start in the frame before the displayed one, so both are drawn
+                system.io.video.render = i + 2 >= frames;
+                while !system.io.paused && cyc < CPU_CLOCK / 60 {
+                    system.backup_cpu.step(&mut system.io);
 
-                let added = system.cpu.step(&mut system.io);
-                cyc += added;
-                system.io.video.cycles += added;
+                    system.io.paused = watchpoints.check(
+                        system.io.last_addr,
+                        system.io.last_is_read,
+                        system.io.last_is_mem,
+                    );
+                    if system.io.paused {
+                        system.backup_cpu = system.cpu.clone();
+                        system.backup_cpu.side_effects = false;
+                        break;
+                    }
 
-                system.io.paused = breakpoints.check(system.backup_cpu.pc);
-                if system.io.paused {
-                    break;
+                    let added = system.cpu.step(&mut system.io);
+                    cyc += added;
+                    system.io.add_cycles(added);
+                    system.io.update_irqs(&mut system.cpu);
+
+                    system.io.paused = breakpoints.check(system.backup_cpu.pc);
+                    if system.io.paused {
+                        break;
+                    }
                 }
-            }
 
-            if cyc >= CPU_CLOCK / 60 {
-                cyc -= CPU_CLOCK / 60;
-                system.io.video.cycles -= CPU_CLOCK / 60;
-
-                system.io.keyboard.set_btns_pressed(&input);
-                if system.io.key_irq_vector != 0 {
-                    if system.io.keyboard.key_pressed != system.io.last_key_press {
-                        system.io.sub_vals[1] = system.io.keyboard.check_press() as u8;
-                        system.io.sub_vals[0] = system.io.keyboard.check_shift();
-                        system.io.sub_cmd_len = 2;
-                        system.cpu.assert_irq(system.io.key_irq_vector);
-                        system.io.sub_cmd = 0xe6;
-                        system.io.sub_obf = 0x00;
+                if cyc >= CPU_CLOCK / 60 {
+                    cyc -= CPU_CLOCK / 60;
+                    audio.push(&system.io.psg.take_samples());
+
+                    let (held, mods_held, joysticks) =
+                        scan_inputs(&mut keymap, &mut joystick, &|keycode| {
+                            input.key_held(keycode)
+                        });
+                    system.io.subcpu.scan_keys(held, mods_held);
+                    system.io.set_joysticks(joysticks);
+                    emulated += 1;
+                }
+            }
+            system.io.video.render = true;
+
+            let displayed = frames > 0 || system.io.paused;
+            if displayed {
+                if system.io.video.frame_size() != buffer_size {
+                    buffer_size = system.io.video.frame_size();
+                    if let Err(err) = pixels.resize_buffer(buffer_size.0, buffer_size.1) {
+                        error!("pixels.resize_buffer() failed: {err}");
+                        *control_flow = ControlFlow::Exit;
+                        return;
                     }
-                    system.io.last_key_press = system.io.keyboard.key_pressed;
                 }
+                system.io.video.display(pixels.frame_mut());
+                window.request_redraw();
             }
-
-            system
-                .io
-                .video
-                .display(pixels.frame_mut(), &mut vram_viewers);
-            window.request_redraw();
+            timing.frames_done(emulated, displayed);
+            *control_flow = if timing.turbo() {
+                ControlFlow::Poll
+            } else {
+                ControlFlow::WaitUntil(timing.next_frame())
+            };
         }
 
         match event {
@@ -615,6 +624,10 @@ fn main() -> Result<(), Error> {
                     &mut breakpoints,
                     &mut watchpoints,
                     &mut vram_viewers,
+                    &mut audio,
+                    &mut timing,
+                    &mut keymap,
+                    &mut joystick,
                 );
 
                 // Render everything together
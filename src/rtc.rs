@@ -1,3 +1,7 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::constants::CPU_CLOCK;
+
 #[derive(Savefile)]
 pub struct RTC {
     pub day: u8,   // BCD day in month
@@ -7,11 +11,75 @@ pub struct RTC {
     pub hour: u8,   // BCD
     pub minute: u8, // BCD
     pub second: u8, // BCD
+
+    cycles: u32,
+    // Seconds the clock is ahead of the host's local time, updated when the clock is set or a
+    // state is saved, so a loaded state carries on from the time it was saved at plus the time
+    // since
+    host_offset: i64,
+}
+
+fn to_bcd(value: i64) -> u8 {
+    (((value / 10) << 4) | (value % 10)) as u8
+}
+
+fn from_bcd(value: u8) -> i64 {
+    ((value >> 4) * 10 + (value & 0xf)) as i64
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+#[cfg(unix)]
+fn utc_offset(utc: i64) -> i64 {
+    let time = utc as libc::time_t;
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return 0;
+        }
+        tm.tm_gmtoff as i64
+    }
+}
+
+// The host's time zone is only known on unix, elsewhere host time is UTC
+#[cfg(not(unix))]
+fn utc_offset(_: i64) -> i64 {
+    0
+}
+
+// Host local time in seconds since 1970-01-01 00:00:00
+fn host_time() -> i64 {
+    let utc = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs() as i64,
+        Err(_) => 0,
+    };
+    utc + utc_offset(utc)
 }
 
 impl RTC {
     pub fn new() -> Self {
-        Self {
+        let mut rtc = Self {
             day: 0,
             month: 1,
             weekday: 0,
@@ -19,6 +87,94 @@ impl RTC {
             hour: 0,
             minute: 0,
             second: 0,
+
+            cycles: 0,
+            host_offset: 0,
+        };
+        // 1980/01/01 00:00:00
+        rtc.set_seconds(days_from_civil(1980, 1, 1) * 86400);
+        rtc.host_offset = rtc.seconds() - host_time();
+        rtc
+    }
+
+    fn seconds(&self) -> i64 {
+        // 2-digit years from 80 are 19xx
+        let year = match from_bcd(self.year) {
+            year @ 80.. => 1900 + year,
+            year => 2000 + year,
+        };
+        let days = days_from_civil(year, self.month as i64, from_bcd(self.day));
+        days * 86400
+            + from_bcd(self.hour) * 3600
+            + from_bcd(self.minute) * 60
+            + from_bcd(self.second)
+    }
+
+    fn set_seconds(&mut self, seconds: i64) {
+        let days = seconds.div_euclid(86400);
+        let time = seconds.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        self.year = to_bcd(year.rem_euclid(100));
+        self.month = month as u8;
+        self.day = to_bcd(day);
+        // 1970/01/01 was a thursday, 0 is sunday
+        self.weekday = (days + 4).rem_euclid(7) as u8;
+        self.hour = to_bcd(time / 3600);
+        self.minute = to_bcd(time / 60 % 60);
+        self.second = to_bcd(time % 60);
+    }
+
+    pub fn sync_host(&mut self) {
+        self.set_seconds(host_time());
+        self.host_offset = 0;
+    }
+
+    pub fn save_host_offset(&mut self) {
+        self.host_offset = self.seconds() - host_time();
+    }
+
+    // Catch up with the host after loading a state
+    pub fn resync(&mut self) {
+        self.set_seconds(host_time() + self.host_offset);
+    }
+
+    pub fn set_date(&mut self, day: u8, month_weekday: u8, year: u8) {
+        self.day = day;
+        self.month = month_weekday >> 4;
+        self.weekday = month_weekday & 0xf;
+        self.year = year;
+        self.save_host_offset();
+    }
+
+    pub fn set_time(&mut self, hour: u8, minute: u8, second: u8) {
+        self.hour = hour;
+        self.minute = minute;
+        self.second = second;
+        self.cycles = 0;
+        self.save_host_offset();
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles;
+        while self.cycles >= CPU_CLOCK {
+            self.cycles -= CPU_CLOCK;
+            let seconds = self.seconds() + 1;
+            self.set_seconds(seconds);
         }
     }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.menu_button("RTC", |ui| {
+            ui.label(format!(
+                "{:02x}/{:02}/{:02x} {:02x}:{:02x}:{:02x}",
+                self.year, self.month, self.day, self.hour, self.minute, self.second
+            ));
+            if ui.button("Sync to host time").clicked() {
+                self.sync_host();
+                ui.close_menu();
+            }
+            #[cfg(not(unix))]
+            ui.label("Host time is UTC on this platform");
+        });
+    }
 }
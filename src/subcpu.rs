@@ -0,0 +1,230 @@
+use crate::cmt::CMT;
+use crate::joystick::{JOY_DOWN, JOY_LEFT, JOY_RIGHT, JOY_TRIGGER_1, JOY_TRIGGER_2, JOY_UP};
+use crate::keyboard::Keyboard;
+use crate::rtc::RTC;
+
+// CPU cycles the 80C49 takes to take a byte off the bus and act on it
+const BUSY_CYCLES: u32 = 160;
+
+#[derive(Savefile)]
+pub struct SubCPU {
+    pub keyboard: Keyboard,
+    pub rtc: RTC,
+
+    // Command waiting for its parameter bytes
+    cmd: u8,
+    params: [u8; 6],
+    param_len: usize,
+    param_ptr: usize,
+
+    out: [u8; 6],
+    out_len: usize,
+    out_ptr: usize,
+    key_i: usize,
+    busy: u32,
+
+    pub key_irq_vector: u8,
+    pub key_irq_pending: bool,
+    pub key_irq_in_service: bool,
+    // Key data waiting for the guest to finish with the last command's output
+    key_data_pending: bool,
+    // Joystick 1 also shows up in the game keys
+    pub joystick: u8,
+
+    tv_ctrl: u8,
+    timers: [[u8; 6]; 8],
+}
+
+impl SubCPU {
+    pub fn new() -> Self {
+        Self {
+            keyboard: Keyboard::new(),
+            rtc: RTC::new(),
+
+            cmd: 0,
+            params: [0; 6],
+            param_len: 0,
+            param_ptr: 0,
+
+            out: [0; 6],
+            out_len: 0,
+            out_ptr: 0,
+            key_i: 0,
+            busy: 0,
+
+            key_irq_vector: 0,
+            key_irq_pending: false,
+            key_irq_in_service: false,
+            key_data_pending: false,
+            joystick: 0,
+
+            tv_ctrl: 0,
+            timers: [[0; 6]; 8],
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        self.busy = self.busy.saturating_sub(cycles);
+        self.rtc.tick(cycles);
+        self.send_key_data();
+    }
+
+    pub fn status(&self) -> u8 {
+        /*
+        -x-- ---- IBF, set while the sub CPU hasn't taken the last byte written
+        --x- ---- OBF, active low, clear when there's a byte to read
+        */
+        if self.busy != 0 {
+            return 0x60;
+        }
+        match self.out_len {
+            0 => 0x20,
+            _ => 0x00,
+        }
+    }
+
+    fn respond(&mut self, vals: &[u8]) {
+        self.out[..vals.len()].copy_from_slice(vals);
+        self.out_len = vals.len();
+        self.out_ptr = 0;
+    }
+
+    fn game_keys(&self) -> [u8; 3] {
+        // Triggers on Z and X, directions on the numpad
+        let mut game_keys = self.keyboard.game_keys;
+        let buttons = [
+            (JOY_TRIGGER_1, 0, 0x04),
+            (JOY_TRIGGER_2, 0, 0x02),
+            (JOY_UP, 1, 0x40),
+            (JOY_LEFT, 1, 0x10),
+            (JOY_RIGHT, 1, 0x08),
+            (JOY_DOWN, 1, 0x02),
+        ];
+        for (flag, line, bit) in buttons {
+            if (self.joystick & flag) != 0 {
+                game_keys[line] |= bit;
+            }
+        }
+        game_keys
+    }
+
+    fn key_data(&self) -> [u8; 2] {
+        [self.keyboard.check_shift(), self.keyboard.key_pressed]
+    }
+
+    pub fn read(&mut self, side_effects: bool) -> u8 {
+        if self.out_len == 0 {
+            // Nothing left to send, the last key data is repeated
+            let ret = self.out[self.key_i];
+            if side_effects {
+                self.key_i = (self.key_i + 1) % 2;
+            }
+            return ret;
+        }
+
+        let ret = self.out[self.out_ptr];
+        if side_effects {
+            self.out_ptr += 1;
+            self.out_len -= 1;
+            if self.out_len == 0 {
+                self.out_ptr = 0;
+            }
+        }
+        ret
+    }
+
+    pub fn write(&mut self, value: u8, cmt: &mut CMT) {
+        self.busy = BUSY_CYCLES;
+
+        if self.param_len != 0 {
+            self.params[self.param_ptr] = value;
+            self.param_ptr += 1;
+            if self.param_ptr == self.param_len {
+                self.param_len = 0;
+                self.param_ptr = 0;
+                self.run_params(cmt);
+            }
+            return;
+        }
+
+        self.cmd = value;
+        match value {
+            0xd0..=0xd7 => self.param_len = 6,
+            0xd8..=0xdf => {
+                // Timer read, returns what was set by 0xd0-0xd7
+                let timer = self.timers[(value & 7) as usize];
+                self.respond(&timer);
+            }
+            0xe3 => {
+                let game_keys = self.game_keys();
+                self.respond(&game_keys);
+            }
+            0xe4 => self.param_len = 1,
+            0xe5 => {
+                // Key buffer clear
+                self.key_irq_pending = false;
+                self.key_data_pending = false;
+                self.out_len = 0;
+                self.out_ptr = 0;
+            }
+            0xe6 => {
+                self.key_data_pending = false;
+                let key_data = self.key_data();
+                self.respond(&key_data);
+            }
+            0xe7 => self.param_len = 1,
+            0xe8 => self.respond(&[self.tv_ctrl]),
+            0xe9 => self.param_len = 1,
+            0xea => self.respond(&[cmt.current_cmd()]),
+            0xeb => self.respond(&[cmt.tape_status()]),
+            0xec => self.param_len = 3,
+            0xed => {
+                let rtc = &self.rtc;
+                let date = [rtc.day, (rtc.month << 4) | (rtc.weekday & 0xf), rtc.year];
+                self.respond(&date);
+            }
+            0xee => self.param_len = 3,
+            0xef => {
+                let rtc = &self.rtc;
+                let time = [rtc.hour, rtc.minute, rtc.second];
+                self.respond(&time);
+            }
+            // Undocumented commands are ignored
+            _ => (),
+        }
+    }
+
+    fn run_params(&mut self, cmt: &mut CMT) {
+        let params = self.params;
+        match self.cmd {
+            0xd0..=0xd7 => self.timers[(self.cmd & 7) as usize] = params,
+            0xe4 => self.key_irq_vector = params[0],
+            0xe7 => self.tv_ctrl = params[0],
+            0xe9 => cmt.command(params[0]),
+            0xec => self.rtc.set_date(params[0], params[1], params[2]),
+            0xee => self.rtc.set_time(params[0], params[1], params[2]),
+            _ => (),
+        }
+    }
+
+    // Called once a frame with the keys from Keymap::scan
+    pub fn scan_keys(&mut self, held: u128, mods_held: u8) {
+        let changed = self.keyboard.update(held, mods_held);
+        if changed && self.key_irq_vector != 0 {
+            self.key_data_pending = true;
+            self.send_key_data();
+        }
+    }
+
+    // Key data goes out once the guest is done with the current command
+    fn send_key_data(&mut self) {
+        if !self.key_data_pending || self.busy != 0 || self.param_len != 0 || self.out_len != 0 {
+            return;
+        }
+        self.key_data_pending = false;
+        let key_data = self.key_data();
+        self.respond(&key_data);
+        self.cmd = 0xe6;
+        self.key_irq_pending = true;
+    }
+}
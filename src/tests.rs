@@ -1,9 +1,27 @@
 #[cfg(test)]
 mod tests {
+    use crate::audio::WavWriter;
+    use crate::ay8910::AY8910;
+    use crate::cmt::{Tape, CMT};
+    use crate::constants::CPU_CLOCK;
+    use crate::ctc::CTC;
+    use crate::disk::{Disk, DiskFormat};
+    use crate::fdc::FDC;
+    use crate::joystick::{Joystick, JoystickInput, JOY_LEFT, JOY_RIGHT, JOY_TRIGGER_1, JOY_UP};
+    use crate::keyboard::{
+        key_index, Keyboard, KEYMOD_CAPS, KEYMOD_CTRL, KEYMOD_GRAPH, KEYMOD_KANA, KEYMOD_SHIFT,
+    };
+    use crate::keymap::{Keymap, KeymapMode, Layout};
+    use crate::subcpu::SubCPU;
+    use crate::timing::{Speed, Timing};
+    use crate::video::{double_height_font, Deinterlace, Video};
     use crate::z80::{FDEPhase, Z80, Z80IO};
+    use crate::{scan_inputs, IO};
+    use egui_winit::winit::event::VirtualKeyCode;
     use serde::Deserialize;
     use std::fs::{metadata, File};
     use std::io::Read;
+    use std::time::Duration;
 
     #[derive(Deserialize)]
     struct Z80State {
@@ -6738,4 +6756,1057 @@ mod tests {
             .collect::<String>();
         println!("{}", final_str);
     }
+
+    fn write_psg(psg: &mut AY8910, reg: u8, val: u8) {
+        psg.set_addr(reg);
+        psg.write_data(val);
+    }
+
+    #[test]
+    fn test_psg_tone() {
+        let mut psg = AY8910::new();
+        write_psg(&mut psg, 0, 2);
+        write_psg(&mut psg, 7, 0x3e);
+        write_psg(&mut psg, 8, 0x0f);
+        psg.tick(16 * 8);
+        let high = 1.0 / 3.0;
+        assert_eq!(
+            psg.take_samples(),
+            vec![0.0, high, high, 0.0, 0.0, high, high, 0.0]
+        );
+    }
+
+    #[test]
+    fn test_psg_envelope_attack_hold() {
+        let mut psg = AY8910::new();
+        write_psg(&mut psg, 7, 0x3f);
+        write_psg(&mut psg, 8, 0x10);
+        write_psg(&mut psg, 11, 1);
+        write_psg(&mut psg, 13, 0x0d);
+        // 16 envelope steps, each lasting 2 ticks
+        psg.tick(16 * 64);
+        let samples = psg.take_samples();
+        assert!(samples.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(samples[0], 0.0106 / 3.0);
+        assert_eq!(*samples.last().unwrap(), 1.0 / 3.0);
+    }
+
+    #[test]
+    fn test_psg_register_masks() {
+        let mut psg = AY8910::new();
+        write_psg(&mut psg, 1, 0xff);
+        assert_eq!(psg.read_data(), 0x0f);
+        // Port A is an input while R7 bit 6 is clear
+        write_psg(&mut psg, 14, 0x12);
+        assert_eq!(psg.read_data(), 0xff);
+        write_psg(&mut psg, 7, 0x40);
+        psg.set_addr(14);
+        assert_eq!(psg.read_data(), 0x12);
+    }
+
+    #[test]
+    fn test_wav_writer_header() {
+        let fname = format!("x1-emu-test-{}.wav", std::process::id());
+        let path = std::env::temp_dir().join(fname);
+        let path = path.to_str().unwrap();
+        let mut wav = WavWriter::create(path, 48000).unwrap();
+        wav.write(&[0.0, 1.0, -1.0]).unwrap();
+        wav.write(&[0.5]).unwrap();
+
+        let data = get_file_as_byte_vec(&String::from(path));
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 36 + 8);
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 48000);
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 8);
+        assert_eq!(i16::from_le_bytes([data[46], data[47]]), i16::MAX);
+        assert_eq!(i16::from_le_bytes([data[48], data[49]]), -i16::MAX);
+    }
+
+    #[test]
+    fn test_ctc_timer_daisy_chain() {
+        let mut ctc = CTC::new();
+        ctc.write(0, 0x10);
+        for channel in 0..2 {
+            // Timer, prescaler 16, irq enabled
+            ctc.write(channel, 0x85);
+            ctc.write(channel, 2);
+        }
+        ctc.tick(31);
+        assert_eq!(ctc.irq_vector(), None);
+        assert_eq!(ctc.read(0), 1);
+        ctc.tick(1);
+        assert_eq!(ctc.irq_vector(), Some(0x10));
+        assert_eq!(ctc.read(0), 2);
+
+        // Channel 0 in service blocks channel 1 until RETI
+        ctc.irq_ack();
+        assert_eq!(ctc.irq_vector(), None);
+        ctc.irq_reti();
+        assert_eq!(ctc.irq_vector(), Some(0x12));
+    }
+
+    #[test]
+    fn test_irq_ack_goes_to_asserted_device() {
+        let mut io = IO::new(vec![0; 0x1000], vec![0; 0x1000], vec![0; 0x800]);
+        let mut cpu = Z80::new(true);
+        io.ctc.write(0, 0x10);
+        io.ctc.write(0, 0x85);
+        io.ctc.write(0, 1);
+        io.ctc.tick(16);
+        io.update_irqs(&mut cpu);
+        assert_eq!(cpu.curr_irq_data, 0x10);
+
+        // The key IRQ comes in after the CTC vector was handed to the CPU
+        io.subcpu.key_irq_vector = 0x20;
+        io.subcpu.key_irq_pending = true;
+        cpu.irq_acked = true;
+        io.update_irqs(&mut cpu);
+        assert!(io.subcpu.key_irq_pending);
+        assert!(!io.subcpu.key_irq_in_service);
+        assert_eq!(io.ctc.irq_vector(), None);
+        assert_eq!(cpu.curr_irq_data, 0x20);
+
+        // The key IRQ nests, its RETI comes first
+        cpu.irq_acked = true;
+        io.update_irqs(&mut cpu);
+        assert!(io.subcpu.key_irq_in_service);
+        assert!(!cpu.irq_req);
+        cpu.reti_executed = true;
+        io.update_irqs(&mut cpu);
+        assert!(!io.subcpu.key_irq_in_service);
+        assert!(!cpu.irq_req);
+        cpu.reti_executed = true;
+        io.update_irqs(&mut cpu);
+        assert_eq!(io.ctc.irq_vector(), None);
+        io.ctc.tick(16);
+        io.update_irqs(&mut cpu);
+        assert!(cpu.irq_req);
+        assert_eq!(cpu.curr_irq_data, 0x10);
+    }
+
+    #[test]
+    fn test_ctc_counter_chained_from_channel_0() {
+        let mut ctc = CTC::new();
+        ctc.write(0, 0x05);
+        ctc.write(0, 1);
+        // Counter, irq enabled, time constant 3
+        ctc.write(3, 0xc5);
+        ctc.write(3, 3);
+        ctc.tick(16 * 2);
+        assert_eq!(ctc.read(3), 1);
+        assert_eq!(ctc.irq_vector(), None);
+        ctc.tick(16);
+        assert_eq!(ctc.irq_vector(), Some(0x06));
+    }
+
+    #[test]
+    fn test_timing_frames_due() {
+        let frame = Duration::from_secs_f64(1.0 / 60.0);
+        let margin = Duration::from_millis(1);
+
+        let mut timing = Timing::new();
+        let start = timing.next_frame();
+        assert_eq!(timing.frames_due_at(start, false), 1);
+        assert_eq!(timing.frames_due_at(start + frame / 2, false), 0);
+        assert_eq!(timing.frames_due_at(start + frame * 3 + margin, false), 3);
+
+        // 200% runs 2 frames per 60th of a second, 50% 1 every other one
+        let mut timing = Timing::new();
+        timing.speed = Speed::Double;
+        let start = timing.next_frame();
+        assert_eq!(timing.frames_due_at(start, false), 1);
+        assert_eq!(timing.frames_due_at(start + frame + margin, false), 2);
+        let mut timing = Timing::new();
+        timing.speed = Speed::Half;
+        let start = timing.next_frame();
+        assert_eq!(timing.frames_due_at(start, false), 1);
+        assert_eq!(timing.frames_due_at(start + frame + margin, false), 0);
+        assert_eq!(timing.frames_due_at(start + frame * 2 + margin, false), 1);
+    }
+
+    #[test]
+    fn test_timing_stall_and_pause() {
+        let frame = Duration::from_secs_f64(1.0 / 60.0);
+        let margin = Duration::from_millis(1);
+
+        // After a stall at most 4 frames are skipped, the rest of the time is dropped
+        let mut timing = Timing::new();
+        let start = timing.next_frame();
+        assert_eq!(timing.frames_due_at(start, false), 1);
+        let stalled = start + Duration::from_secs(1);
+        assert_eq!(timing.frames_due_at(stalled, false), 5);
+        assert_eq!(timing.frames_due_at(stalled + frame / 2, false), 0);
+
+        // Nothing is owed for the time spent paused
+        let resumed = stalled + Duration::from_secs(10);
+        assert_eq!(timing.frames_due_at(resumed, true), 0);
+        assert_eq!(timing.frames_due_at(resumed + frame / 2, false), 0);
+        assert_eq!(timing.frames_due_at(resumed + frame + margin, false), 1);
+    }
+
+    #[test]
+    fn test_cmt_tap_playback() {
+        let mut tap = vec![0u8; 0x28];
+        tap[0..4].copy_from_slice(b"TAPE");
+        tap[4..8].copy_from_slice(b"test");
+        tap[0x1b] = 0x01;
+        // 1 sample every 1000 CPU cycles
+        tap[0x1c..0x20].copy_from_slice(&4000u32.to_le_bytes());
+        tap[0x20..0x24].copy_from_slice(&4u32.to_le_bytes());
+        tap.push(0b1011_0000);
+
+        let mut cmt = CMT::new();
+        assert_eq!(cmt.tape_status(), 0x05);
+        cmt.insert(tap).unwrap();
+        assert_eq!(cmt.tape_status(), 0x07);
+        assert!(!cmt.read_bit());
+
+        cmt.command(0x02);
+        assert_eq!(cmt.current_cmd(), 0x02);
+        let mut bits = vec![];
+        for _ in 0..4 {
+            bits.push(cmt.read_bit());
+            cmt.tick(1000);
+        }
+        assert_eq!(bits, vec![true, false, true, true]);
+
+        // Stopped at the end of the tape
+        assert_eq!(cmt.tape_status(), 0x06);
+        assert!(!cmt.test_bit(true));
+        assert!(cmt.test_bit(true));
+    }
+
+    #[test]
+    fn test_cmt_record() {
+        let mut cmt = CMT::new();
+        cmt.insert_blank();
+        cmt.command(0x0a);
+        // 16kHz recording, 1 sample every 250 CPU cycles
+        for level in [true, true, false, true] {
+            cmt.set_output(level);
+            cmt.tick(250);
+        }
+        cmt.command(0x01);
+
+        let tap = cmt.tape().unwrap().to_tap();
+        assert_eq!(&tap[0..4], b"TAPE");
+        assert_eq!(tap.len(), 0x28 + 1);
+        assert_eq!(tap[0x28], 0b1101_0000);
+        let tape = Tape::parse(&tap).unwrap();
+        assert_eq!(tape.to_tap(), tap);
+    }
+
+    fn build_d88(sectors: &[(u8, u8, u8, u8, bool, Vec<u8>)]) -> Vec<u8> {
+        let mut d88 = vec![0u8; 0x2b0];
+        d88[0..4].copy_from_slice(b"TEST");
+        d88[0x20..0x24].copy_from_slice(&0x2b0u32.to_le_bytes());
+        for (c, h, r, n, deleted, data) in sectors {
+            d88.extend([*c, *h, *r, *n]);
+            d88.extend((sectors.len() as u16).to_le_bytes());
+            d88.extend([0, if *deleted { 0x10 } else { 0 }, 0, 0, 0, 0, 0, 0]);
+            d88.extend((data.len() as u16).to_le_bytes());
+            d88.extend(data);
+        }
+        let len = d88.len() as u32;
+        d88[0x1c..0x20].copy_from_slice(&len.to_le_bytes());
+        d88
+    }
+
+    #[test]
+    fn test_disk_d88() {
+        let d88 = build_d88(&[
+            (0, 0, 1, 0, false, vec![0x11; 0x80]),
+            (0, 0, 3, 2, true, vec![0x33; 0x200]),
+        ]);
+        let disk = Disk::from_bytes(&d88).unwrap();
+        assert!(disk.format == DiskFormat::D88);
+        assert_eq!(disk.name, "TEST");
+        let sector = disk.find_sector(0, 0, 3).unwrap();
+        assert!(sector.deleted);
+        assert_eq!(sector.n, 2);
+        assert_eq!(sector.data, vec![0x33; 0x200]);
+        assert_eq!(disk.find_sector(0, 0, 1).unwrap().data.len(), 0x80);
+        assert!(disk.find_sector(0, 0, 2).is_none());
+        assert!(disk.find_sector(0, 1, 1).is_none());
+
+        // Single density sectors and the image's write protect flag are saved as they were
+        let mut d88 = d88;
+        d88[0x1a] = 0x10;
+        d88[0x2b0 + 6] = 0x40;
+        let mut disk = Disk::from_bytes(&d88).unwrap();
+        assert!(disk.write_protected);
+        disk.write_protected = false;
+        assert_eq!(disk.to_bytes(), d88);
+    }
+
+    #[test]
+    fn test_disk_d88_bad_track_offset() {
+        let mut d88 = build_d88(&[(0, 0, 1, 0, false, vec![0x11; 0x80])]);
+        d88[0x24..0x28].copy_from_slice(&0x10u32.to_le_bytes());
+        assert!(Disk::from_bytes(&d88).is_err());
+        d88[0x24..0x28].copy_from_slice(&0x10000u32.to_le_bytes());
+        assert!(Disk::from_bytes(&d88).is_err());
+    }
+
+    #[test]
+    fn test_disk_raw_2d() {
+        let mut raw = vec![0u8; 80 * 16 * 0x100];
+        // Cylinder 1, side 1, sector 3
+        raw[(3 * 16 + 2) * 0x100] = 0xaa;
+        let disk = Disk::from_bytes(&raw).unwrap();
+        assert!(disk.format == DiskFormat::Raw2D);
+        let sector = disk.find_sector(1, 1, 3).unwrap();
+        assert_eq!((sector.c, sector.h, sector.r, sector.n), (1, 1, 3, 1));
+        assert_eq!(sector.data[0], 0xaa);
+    }
+
+    #[test]
+    fn test_disk_overlay_write_back() {
+        let d88 = build_d88(&[
+            (0, 0, 1, 1, false, vec![0x11; 0x100]),
+            (0, 0, 2, 1, true, vec![0x22; 0x100]),
+        ]);
+        let fname = format!("x1_overlay_test_{}.d88", std::process::id());
+        let path = std::env::temp_dir().join(fname);
+        let path = path.to_str().unwrap();
+        std::fs::write(path, &d88).unwrap();
+        let _ = std::fs::remove_file(format!("{path}.ovl"));
+
+        let mut disk = Disk::open(path).unwrap();
+        assert!(!disk.use_overlay);
+        disk.use_overlay = true;
+        let mut fdc = FDC::new(disk);
+        fdc.sector = 2;
+        fdc.cmd(0xa0);
+        fdc_write(&mut fdc, &[0x55; 0x100]);
+        fdc.eject(0);
+
+        // The original is untouched and the overlay is picked up on the next open
+        assert_eq!(std::fs::read(path).unwrap(), d88);
+        let disk = Disk::open(path).unwrap();
+        assert!(disk.use_overlay);
+        assert_eq!(disk.name, "TEST");
+        assert_eq!(disk.find_sector(0, 0, 1).unwrap().data, vec![0x11; 0x100]);
+        let sector = disk.find_sector(0, 0, 2).unwrap();
+        assert!(!sector.deleted);
+        assert_eq!(sector.data, vec![0x55; 0x100]);
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(format!("{path}.ovl")).unwrap();
+    }
+
+    #[test]
+    fn test_fdc_drive_select() {
+        let mut fdc = FDC::none();
+        for (drive, val) in [(0, 0x11), (2, 0x33)] {
+            let mut raw = vec![val; 80 * 16 * 0x100];
+            raw[2 * 16 * 0x100] = val + 1;
+            fdc.insert(drive, Disk::from_bytes(&raw).unwrap());
+        }
+
+        // Drive 2 seeks while drive 0 stays on cylinder 0
+        fdc.set_floppy(0x82);
+        fdc.write_data(1);
+        fdc.cmd(0x10);
+        fdc_wait(&mut fdc);
+        fdc.sector = 1;
+        fdc.cmd(0x80);
+        assert_eq!(fdc_read(&mut fdc)[0], 0x34);
+
+        fdc.set_floppy(0x80);
+        fdc.cmd(0x80);
+        fdc_wait(&mut fdc);
+        assert_eq!(fdc.status(true) & 0x10, 0x10);
+        fdc.track = 0;
+        fdc.cmd(0x80);
+        assert_eq!(fdc_read(&mut fdc)[0], 0x11);
+
+        fdc.set_floppy(0x81);
+        assert_eq!(fdc.status(true) & 0x80, 0x80);
+        fdc.cmd(0x80);
+        assert_eq!(fdc.status(true) & 1, 0);
+
+        // A command keeps the drive it started on when another bay is selected
+        fdc.set_floppy(0x82);
+        fdc.track = 1;
+        fdc.cmd(0xa0);
+        fdc_feed(&mut fdc, &[0x55]);
+        fdc.set_floppy(0x81);
+        fdc_write(&mut fdc, &[0x55; 0xff]);
+        fdc.set_floppy(0x82);
+        fdc.cmd(0x80);
+        assert_eq!(fdc_read(&mut fdc), vec![0x55; 0x100]);
+
+        // Its disk being ejected ends the command as not ready
+        fdc.cmd(0xf0);
+        fdc_feed(&mut fdc, &[0x4e; 0x10]);
+        fdc.eject(2);
+        fdc_wait(&mut fdc);
+        assert_eq!(fdc.status(true) & 0x81, 0x80);
+    }
+
+    fn fdc_wait(fdc: &mut FDC) {
+        while fdc.status(true) & 1 != 0 {
+            fdc.tick(16);
+        }
+    }
+
+    fn fdc_read(fdc: &mut FDC) -> Vec<u8> {
+        let mut data = vec![];
+        loop {
+            fdc.tick(16);
+            let status = fdc.status(true);
+            if status & 2 != 0 {
+                data.push(fdc.read_data(true));
+            } else if status & 1 == 0 {
+                break;
+            }
+        }
+        assert_eq!(fdc.status(true) & 0x04, 0);
+        data
+    }
+
+    // Writes data as it's requested, the command carries on
+    fn fdc_feed(fdc: &mut FDC, data: &[u8]) {
+        for val in data {
+            while fdc.status(true) & 2 == 0 {
+                assert!(fdc.status(true) & 1 != 0);
+                fdc.tick(16);
+            }
+            fdc.write_data(*val);
+        }
+    }
+
+    fn fdc_write(fdc: &mut FDC, data: &[u8]) {
+        fdc_feed(fdc, data);
+        fdc_wait(fdc);
+        assert_eq!(fdc.status(true) & 0x04, 0);
+    }
+
+    #[test]
+    fn test_fdc_commands() {
+        let mut raw = vec![0u8; 80 * 16 * 0x100];
+        raw[(3 * 16 + 15) * 0x100] = 0xaa;
+        let mut fdc = FDC::new(Disk::from_bytes(&raw).unwrap());
+
+        // Seek to cylinder 1 with verify, then select side 1
+        fdc.write_data(1);
+        fdc.cmd(0x1c);
+        assert_eq!(fdc.status(true) & 1, 1);
+        fdc_wait(&mut fdc);
+        assert_eq!(fdc.status(true) & 0x15, 0);
+        assert_eq!(fdc.track, 1);
+        fdc.set_floppy(0x10);
+
+        // Multi-sector read runs off the end of the track
+        fdc.sector = 15;
+        fdc.cmd(0x90);
+        let data = fdc_read(&mut fdc);
+        assert_eq!(data.len(), 0x200);
+        assert_eq!(data[0x100], 0xaa);
+        assert_eq!(fdc.status(true) & 0x10, 0x10);
+
+        // Bytes that aren't read in time are lost
+        fdc.sector = 1;
+        fdc.cmd(0x80);
+        fdc_wait(&mut fdc);
+        assert_eq!(fdc.status(true) & 0x04, 0x04);
+
+        fdc.sector = 2;
+        fdc.cmd(0xa0);
+        let data: Vec<u8> = (0..=0xff).collect();
+        fdc_write(&mut fdc, &data);
+        assert_eq!(fdc.status(true) & 0x10, 0);
+        fdc.cmd(0x80);
+        assert_eq!(fdc_read(&mut fdc), data);
+
+        // Format the track with 2 sectors of 128 bytes
+        let mut raw_track = vec![0x4e; 80];
+        for r in [1, 2] {
+            raw_track.extend([0xf5, 0xf5, 0xf5, 0xfe, 1, 1, r, 0, 0xf7]);
+            raw_track.extend([0x4e; 22]);
+            raw_track.extend([0xf5, 0xf5, 0xf5, 0xfb]);
+            raw_track.extend([r; 0x80]);
+            raw_track.extend([0xf7]);
+        }
+        raw_track.resize(6250, 0x4e);
+        fdc.cmd(0xf0);
+        fdc_write(&mut fdc, &raw_track);
+        fdc.sector = 2;
+        fdc.cmd(0x80);
+        assert_eq!(fdc_read(&mut fdc), vec![2; 0x80]);
+
+        // Sector 1 is the next ID to pass under the head
+        fdc.cmd(0xc0);
+        let id = fdc_read(&mut fdc);
+        assert_eq!(id[..4], [1, 1, 1, 0]);
+        assert_eq!(fdc.sector, 1);
+
+        // Step out twice, track 0 is flagged by type I status
+        fdc.cmd(0x70);
+        fdc_wait(&mut fdc);
+        fdc.cmd(0x70);
+        fdc_wait(&mut fdc);
+        assert_eq!(fdc.track, 0xff);
+        assert_eq!(fdc.status(true) & 0x04, 0x04);
+        fdc.sector = 1;
+        fdc.cmd(0x80);
+        fdc_wait(&mut fdc);
+        assert_eq!(fdc.status(true) & 0x11, 0x10);
+    }
+
+    fn video_run_to_line(video: &mut Video, line: u16) {
+        while video.vpos() != line {
+            video.tick(1);
+        }
+    }
+
+    fn video_run_frame(video: &mut Video) {
+        video_run_to_line(video, 1);
+        video_run_to_line(video, 0);
+    }
+
+    // The last completed frame, with its width
+    fn video_frame(video: &mut Video) -> (Vec<u8>, usize) {
+        let (width, height) = video.frame_size();
+        let mut canvas = vec![0; (width * height * 4) as usize];
+        video.display(&mut canvas);
+        (canvas, width as usize)
+    }
+
+    fn video_pixel(frame: &(Vec<u8>, usize), x: usize, y: usize) -> [u8; 4] {
+        let offs = (y * frame.1 + x) * 4;
+        frame.0[offs..offs + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn test_video_raster_palette_change() {
+        let mut video = Video::new(vec![0; 0x1000], vec![0; 0x800]);
+        // Blue plane set everywhere, so every pixel uses palette 9
+        for addr in 0..0x4000 {
+            video.set_bitmap_data(addr, 0xff);
+        }
+        video.set_blue(0x02);
+
+        // Switch the palette to black halfway down the visible area
+        video_run_to_line(&mut video, 100);
+        video.set_blue(0x00);
+        video_run_to_line(&mut video, 0);
+
+        // 15 kHz lines are doubled
+        let frame = video_frame(&mut video);
+        assert_eq!(video.frame_size(), (640, 400));
+        assert_eq!(video_pixel(&frame, 0, 0), [0, 0, 0xff, 0xff]);
+        assert_eq!(video_pixel(&frame, 639, 199), [0, 0, 0xff, 0xff]);
+        assert_eq!(video_pixel(&frame, 0, 200), [0, 0, 0, 0xff]);
+        assert_eq!(video_pixel(&frame, 639, 399), [0, 0, 0, 0xff]);
+
+        // 80x25 with 8 rasters: 258 lines of 112 characters at 1/24th of the VDP clock
+        video_run_to_line(&mut video, 1);
+        let mut cycles = 0;
+        for line in [0, 1] {
+            while video.vpos() != line {
+                video.tick(1);
+                cycles += 1;
+            }
+        }
+        assert!((64_500..64_700).contains(&cycles), "{}", cycles);
+    }
+
+    #[test]
+    fn test_video_16_raster_text() {
+        // A diagonal line in the 8x16 ANK ROM
+        let mut ank = vec![0; 0x1000];
+        for raster in 0..16 {
+            ank[0x41 * 16 + raster] = 0x80 >> (raster / 2);
+        }
+        let mut video = Video::new(ank, vec![0; 0x800]);
+        video.hd6845s.addr = 9;
+        video.hd6845s.set_addr(15);
+        video.tvram[0] = 0x41;
+        video.avram[0] = 0x07;
+
+        // 514 lines a frame, so not line doubled
+        video_run_frame(&mut video);
+        let frame = video_frame(&mut video);
+        assert_eq!(video.frame_size(), (640, 400));
+        for raster in 0..16 {
+            assert_eq!(video_pixel(&frame, raster / 2, raster), [0xff; 4]);
+            assert_eq!(video_pixel(&frame, raster / 2 + 1, raster), [0, 0, 0, 0xff]);
+        }
+
+        // The ANK ROM reads back through the PCG ports and ignores writes
+        video_run_to_line(&mut video, 5);
+        video.pcg_w(0, 0xff);
+        assert_eq!(video.pcg_r(0), 0x20);
+    }
+
+    #[test]
+    fn test_video_40_columns() {
+        let mut fnt = vec![0; 0x800];
+        fnt[0x41 * 8] = 0x80;
+        let mut video = Video::new(vec![0; 0x1000], fnt);
+        video.hres_320 = true;
+        for (reg, value) in [(0, 0x37), (1, 0x28), (2, 0x2d), (3, 0x34)] {
+            video.hd6845s.addr = reg;
+            video.hd6845s.set_addr(value);
+        }
+        video.tvram[1] = 0x41;
+        video.avram[1] = 0x07;
+
+        video_run_to_line(&mut video, 1);
+        let mut cycles = 0;
+        for line in [0, 1] {
+            while video.vpos() != line {
+                video.tick(1);
+                cycles += 1;
+            }
+        }
+        // Half the characters per line at half the character clock
+        assert!((64_500..64_700).contains(&cycles), "{}", cycles);
+
+        let frame = video_frame(&mut video);
+        assert_eq!(video.frame_size(), (640, 400));
+        assert_eq!(video_pixel(&frame, 15, 0), [0, 0, 0, 0xff]);
+        assert_eq!(video_pixel(&frame, 16, 0), [0xff; 4]);
+        assert_eq!(video_pixel(&frame, 17, 1), [0xff; 4]);
+        assert_eq!(video_pixel(&frame, 18, 0), [0, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn test_video_page_flip() {
+        let mut video = Video::new(vec![0; 0x1000], vec![0; 0x800]);
+        video.set_blue(0x02);
+
+        // Draw into the hidden page 1 while page 0 is displayed
+        video.set_scrn(0x10);
+        video.set_bitmap_data(0, 0xff);
+        assert_eq!(video.get_bitmap_data(0), 0xff);
+        video_run_frame(&mut video);
+        assert_eq!(video_pixel(&video_frame(&mut video), 0, 0), [0, 0, 0, 0xff]);
+
+        // Flip, the CPU now accesses page 0
+        video.set_scrn(0x08);
+        assert_eq!(video.get_bitmap_data(0), 0x00);
+        video_run_frame(&mut video);
+        assert_eq!(
+            video_pixel(&video_frame(&mut video), 0, 0),
+            [0, 0, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn test_double_height_font() {
+        let fnt: Vec<u8> = (0..0x800).map(|i| i as u8).collect();
+        let ank = double_height_font(&fnt);
+        assert_eq!(ank.len(), 0x1000);
+        assert_eq!(ank[0x41 * 16..0x41 * 16 + 4], [0x08, 0x08, 0x09, 0x09]);
+    }
+
+    #[test]
+    fn test_video_skipped_frame() {
+        let mut video = Video::new(vec![0; 0x1000], vec![0; 0x800]);
+        video.set_blue(0x02);
+        video.set_bitmap_data(0, 0xff);
+
+        // The beam runs without drawing, the frame is drawn once rendering is back on
+        video.render = false;
+        video_run_frame(&mut video);
+        video_run_frame(&mut video);
+        assert_eq!(video.vpos(), 0);
+        assert_eq!(video_pixel(&video_frame(&mut video), 0, 0), [0, 0, 0, 0]);
+        video.render = true;
+        video_run_frame(&mut video);
+        assert_eq!(
+            video_pixel(&video_frame(&mut video), 0, 0),
+            [0, 0, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn test_video_simultaneous_write() {
+        let mut video = Video::new(vec![0; 0x1000], vec![0; 0x800]);
+        video.ex_gfxram_w(0x0123, 0xaa);
+        for plane in [0x0000, 0x4000, 0x8000] {
+            assert_eq!(video.get_bitmap_data(0x0123 + plane), 0xaa);
+        }
+
+        // Writing through the green range leaves green alone
+        video.ex_gfxram_w(0xc123, 0x55);
+        assert_eq!(video.get_bitmap_data(0x0123), 0x55);
+        assert_eq!(video.get_bitmap_data(0x4123), 0x55);
+        assert_eq!(video.get_bitmap_data(0x8123), 0xaa);
+        assert_eq!(video.ex_gfxram_r(0x8123), 0xaa);
+
+        // Reads past the planes of page 0 continue into page 1
+        video.set_scrn(0x10);
+        video.ex_gfxram_w(0x0000, 0x11);
+        assert_eq!(video.ex_gfxram_r(0x0000), 0x11);
+        video.set_scrn(0x00);
+        assert_eq!(video.ex_gfxram_r(0x0000), 0x00);
+        assert_eq!(video.ex_gfxram_r(0xc000), 0x11);
+    }
+
+    #[test]
+    fn test_video_cursor_and_scroll() {
+        let mut fnt = vec![0; 0x800];
+        fnt[0x41 * 8] = 0x80;
+        let mut video = Video::new(vec![0; 0x1000], fnt);
+        // Start one row down, with a steady cursor on rasters 6-7 of the second character
+        for (reg, value) in [
+            (10, 0x06),
+            (11, 0x07),
+            (12, 0x00),
+            (13, 80),
+            (14, 0x00),
+            (15, 81),
+        ] {
+            video.hd6845s.addr = reg;
+            video.hd6845s.set_addr(value);
+        }
+        video.tvram[80] = 0x41;
+        video.avram[80] = 0x07;
+        video.avram[81] = 0x02;
+
+        video_run_frame(&mut video);
+        let frame = video_frame(&mut video);
+        assert_eq!(video_pixel(&frame, 0, 0), [0xff; 4]);
+        assert_eq!(video_pixel(&frame, 8, 11), [0, 0, 0, 0xff]);
+        assert_eq!(video_pixel(&frame, 8, 12), [0xff, 0, 0, 0xff]);
+        assert_eq!(video_pixel(&frame, 15, 15), [0xff, 0, 0, 0xff]);
+
+        // Blink mode 1 hides the cursor
+        video.hd6845s.addr = 10;
+        video.hd6845s.set_addr(0x26);
+        video_run_frame(&mut video);
+        assert_eq!(
+            video_pixel(&video_frame(&mut video), 8, 12),
+            [0, 0, 0, 0xff]
+        );
+    }
+
+    #[test]
+    fn test_video_smooth_scroll() {
+        let mut video = Video::new(vec![0; 0x1000], vec![0; 0x800]);
+        video.set_blue(0x02);
+        // Raster 3 of the first bitmap row
+        video.set_bitmap_data(3 * 0x800, 0xff);
+        video.hd6845s.addr = 5;
+        video.hd6845s.set_addr(3);
+
+        for (smooth_scroll, line) in [(false, 3), (true, 0)] {
+            video.smooth_scroll = smooth_scroll;
+            video_run_frame(&mut video);
+            let frame = video_frame(&mut video);
+            for y in 0..8 {
+                let blue = if y == line { 0xff } else { 0 };
+                assert_eq!(video_pixel(&frame, 0, y * 2), [0, 0, blue, 0xff]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_video_interlace() {
+        // Odd rasters of the character are lit
+        let mut ank = vec![0; 0x1000];
+        for raster in (1..16).step_by(2) {
+            ank[0x41 * 16 + raster] = 0xff;
+        }
+        let mut video = Video::new(ank, vec![0; 0x800]);
+        // Interlaced sync and video, 16 raster rows split over two fields
+        for (reg, value) in [(8, 0x03), (9, 0x0f)] {
+            video.hd6845s.addr = reg;
+            video.hd6845s.set_addr(value);
+        }
+        video.tvram[0] = 0x41;
+        video.avram[0] = 0x07;
+
+        // Weave combines both fields
+        video_run_frame(&mut video);
+        video_run_frame(&mut video);
+        let frame = video_frame(&mut video);
+        assert_eq!(video.frame_size(), (640, 400));
+        for y in 0..16 {
+            let pen = if y % 2 == 1 {
+                [0xff; 4]
+            } else {
+                [0, 0, 0, 0xff]
+            };
+            assert_eq!(video_pixel(&frame, 0, y), pen);
+        }
+
+        // Bob doubles the lines of each field
+        video.deinterlace = Deinterlace::Bob;
+        for _ in 0..2 {
+            video_run_frame(&mut video);
+            let frame = video_frame(&mut video);
+            assert_eq!(video_pixel(&frame, 0, 0), video_pixel(&frame, 0, 1));
+        }
+    }
+
+    #[test]
+    fn test_video_priority() {
+        let mut fnt = vec![0; 0x800];
+        fnt[0x41 * 8] = 0x80;
+        let mut video = Video::new(vec![0; 0x1000], fnt);
+        video.set_blue(0x02);
+        for addr in 0..2 {
+            video.set_bitmap_data(addr, 0xff);
+        }
+        // A white character, and the same one reversed
+        video.tvram[0] = 0x41;
+        video.avram[0] = 0x07;
+        video.tvram[1] = 0x41;
+        video.avram[1] = 0x0f;
+
+        let blue = [0, 0, 0xff, 0xff];
+        let white = [0xff; 4];
+        let black = [0, 0, 0, 0xff];
+
+        // Text over graphics, pen 0 is transparent unless reversed
+        video_run_frame(&mut video);
+        let frame = video_frame(&mut video);
+        assert_eq!(video_pixel(&frame, 0, 0), white);
+        assert_eq!(video_pixel(&frame, 1, 0), blue);
+        assert_eq!(video_pixel(&frame, 8, 0), black);
+        assert_eq!(video_pixel(&frame, 9, 0), white);
+
+        // Blue graphics in front of the text
+        video.pri = 0x02;
+        video_run_frame(&mut video);
+        let frame = video_frame(&mut video);
+        for x in [0, 1, 8, 9] {
+            assert_eq!(video_pixel(&frame, x, 0), blue);
+        }
+    }
+
+    fn subcpu_send(subcpu: &mut SubCPU, cmt: &mut CMT, bytes: &[u8]) {
+        for byte in bytes {
+            // Wait for the input buffer to empty like the main CPU does
+            while subcpu.status() & 0x40 != 0 {
+                subcpu.tick(4);
+            }
+            subcpu.write(*byte, cmt);
+        }
+        while subcpu.status() & 0x40 != 0 {
+            subcpu.tick(4);
+        }
+    }
+
+    fn subcpu_recv(subcpu: &mut SubCPU, len: usize) -> Vec<u8> {
+        let mut ret = vec![];
+        for _ in 0..len {
+            assert_eq!(subcpu.status() & 0x20, 0);
+            ret.push(subcpu.read(true));
+        }
+        assert_eq!(subcpu.status() & 0x20, 0x20);
+        ret
+    }
+
+    #[test]
+    fn test_subcpu_commands() {
+        let mut subcpu = SubCPU::new();
+        let mut cmt = CMT::new();
+        assert_eq!(subcpu.status(), 0x20);
+
+        // Busy until the byte is taken
+        subcpu.write(0xe8, &mut cmt);
+        assert_eq!(subcpu.status() & 0x40, 0x40);
+        subcpu_send(&mut subcpu, &mut cmt, &[]);
+        assert_eq!(subcpu_recv(&mut subcpu, 1), [0]);
+
+        // Calendar and time set, then read back
+        subcpu_send(&mut subcpu, &mut cmt, &[0xec, 0x24, 0x93, 0x86]);
+        subcpu_send(&mut subcpu, &mut cmt, &[0xee, 0x12, 0x34, 0x56]);
+        subcpu_send(&mut subcpu, &mut cmt, &[0xed]);
+        assert_eq!(subcpu_recv(&mut subcpu, 3), [0x24, 0x93, 0x86]);
+        subcpu_send(&mut subcpu, &mut cmt, &[0xef]);
+        assert_eq!(subcpu_recv(&mut subcpu, 3), [0x12, 0x34, 0x56]);
+
+        // Timers and TV control
+        subcpu_send(&mut subcpu, &mut cmt, &[0xd3, 1, 2, 3, 4, 5, 6, 0xe7, 0x42]);
+        subcpu_send(&mut subcpu, &mut cmt, &[0xdb]);
+        assert_eq!(subcpu_recv(&mut subcpu, 6), [1, 2, 3, 4, 5, 6]);
+        subcpu_send(&mut subcpu, &mut cmt, &[0xd8]);
+        assert_eq!(subcpu_recv(&mut subcpu, 6), [0; 6]);
+        subcpu_send(&mut subcpu, &mut cmt, &[0xe8]);
+        assert_eq!(subcpu_recv(&mut subcpu, 1), [0x42]);
+
+        // Game keys, key buffer clear and the key IRQ vector
+        subcpu.keyboard.game_keys = [0x80, 0x00, 0x02];
+        subcpu_send(&mut subcpu, &mut cmt, &[0xe3]);
+        assert_eq!(subcpu_recv(&mut subcpu, 3), [0x80, 0x00, 0x02]);
+        subcpu_send(&mut subcpu, &mut cmt, &[0xe4, 0x10, 0xe5]);
+        assert_eq!(subcpu.key_irq_vector, 0x10);
+        assert_eq!(subcpu.status(), 0x20);
+
+        // Unknown commands don't take parameters
+        subcpu_send(&mut subcpu, &mut cmt, &[0x00, 0xff, 0xeb]);
+        assert_eq!(subcpu_recv(&mut subcpu, 1), [cmt.tape_status()]);
+    }
+
+    #[test]
+    fn test_subcpu_key_data_waits_for_reply() {
+        let mut subcpu = SubCPU::new();
+        let mut cmt = CMT::new();
+        subcpu_send(&mut subcpu, &mut cmt, &[0xe4, 0x10, 0xe3]);
+
+        // A key press halfway through the game keys reply doesn't cut into it
+        assert_eq!(subcpu.read(true), 0);
+        subcpu.scan_keys(key_held(&["Q"]), 0);
+        assert!(!subcpu.key_irq_pending);
+        assert_eq!(subcpu_recv(&mut subcpu, 2), [0, 0]);
+        subcpu.tick(4);
+        assert!(subcpu.key_irq_pending);
+        assert_eq!(subcpu_recv(&mut subcpu, 2), [0xbf, b'q']);
+    }
+
+    #[test]
+    fn test_rtc_ticks() {
+        let mut subcpu = SubCPU::new();
+        let mut cmt = CMT::new();
+
+        // New year's eve 1999 rolls over into a saturday
+        subcpu_send(&mut subcpu, &mut cmt, &[0xec, 0x31, 0xc5, 0x99]);
+        subcpu_send(&mut subcpu, &mut cmt, &[0xee, 0x23, 0x59, 0x59]);
+        subcpu.tick(CPU_CLOCK);
+        subcpu_send(&mut subcpu, &mut cmt, &[0xed]);
+        assert_eq!(subcpu_recv(&mut subcpu, 3), [0x01, 0x16, 0x00]);
+        subcpu_send(&mut subcpu, &mut cmt, &[0xef]);
+        assert_eq!(subcpu_recv(&mut subcpu, 3), [0x00, 0x00, 0x00]);
+
+        // Leap day
+        subcpu_send(&mut subcpu, &mut cmt, &[0xec, 0x28, 0x23, 0x24]);
+        subcpu_send(&mut subcpu, &mut cmt, &[0xee, 0x23, 0x59, 0x59]);
+        subcpu.tick(CPU_CLOCK);
+        subcpu_send(&mut subcpu, &mut cmt, &[0xed]);
+        assert_eq!(subcpu_recv(&mut subcpu, 3), [0x29, 0x24, 0x24]);
+    }
+
+    fn key_held(names: &[&str]) -> u128 {
+        let mut held = 0;
+        for name in names {
+            held |= 1 << key_index(name).unwrap();
+        }
+        held
+    }
+
+    // Presses and releases a key, returning the key data sent on the press
+    fn key_type(keyboard: &mut Keyboard, name: &str, mods: u8) -> (u8, u8) {
+        assert!(keyboard.update(key_held(&[name]), mods));
+        let ret = (keyboard.key_pressed, keyboard.check_shift());
+        assert!(keyboard.update(0, mods));
+        assert_eq!(keyboard.key_pressed, 0);
+        ret
+    }
+
+    #[test]
+    fn test_keyboard_codes() {
+        let mut keyboard = Keyboard::new();
+        assert_eq!(key_type(&mut keyboard, "G", 0), (b'g', 0xbf));
+        assert_eq!(key_type(&mut keyboard, "G", KEYMOD_SHIFT), (b'G', 0xbd));
+        assert_eq!(key_type(&mut keyboard, "G", KEYMOD_CTRL), (0x07, 0xbe));
+        assert_eq!(key_type(&mut keyboard, "2", KEYMOD_SHIFT), (b'"', 0xbd));
+        assert_eq!(key_type(&mut keyboard, "F1", KEYMOD_SHIFT), (0x76, 0x3d));
+        assert_eq!(key_type(&mut keyboard, "HOME", 0), (0x0b, 0x3f));
+        assert_eq!(key_type(&mut keyboard, "TEN 5", 0), (b'5', 0x3f));
+        assert_eq!(key_type(&mut keyboard, "BREAK", 0), (0x03, 0xbf));
+
+        // Locks toggle once on press, on the next frame however many frames run
+        keyboard.press_locks(KEYMOD_CAPS);
+        keyboard.update(0, KEYMOD_CAPS);
+        keyboard.update(0, KEYMOD_CAPS);
+        assert_eq!(key_type(&mut keyboard, "Q", 0), (b'Q', 0xb7));
+        keyboard.press_locks(KEYMOD_CAPS | KEYMOD_KANA);
+        assert_eq!(key_type(&mut keyboard, "3", 0), (0xb1, 0xbb));
+        assert_eq!(key_type(&mut keyboard, "3", KEYMOD_SHIFT), (0xa7, 0xb9));
+        assert_eq!(key_type(&mut keyboard, "1", 0), (0xc7, 0xbb));
+        keyboard.press_locks(KEYMOD_KANA);
+        keyboard.press_locks(KEYMOD_GRAPH);
+        assert_eq!(key_type(&mut keyboard, "Q", 0), (b'q', 0xaf));
+        keyboard.press_locks(KEYMOD_GRAPH);
+        keyboard.update(0, 0);
+
+        // Held keys repeat, the game keys follow the held keys
+        assert!(keyboard.update(key_held(&["A", "TEN 8"]), 0));
+        assert_eq!(keyboard.game_keys, [0x10, 0x40, 0x00]);
+        let mut frames = 1;
+        while !keyboard.update(key_held(&["A", "TEN 8"]), 0) {
+            frames += 1;
+        }
+        assert_eq!(frames, 30);
+        assert_eq!(keyboard.check_shift() & 0x20, 0);
+    }
+
+    #[test]
+    fn test_keymap_config() {
+        let mut keymap = Keymap::new(Layout::US);
+        let config = Keymap::new(Layout::JIS).to_config();
+        keymap.parse(&config).unwrap();
+        assert_eq!(keymap.to_config(), config);
+
+        keymap
+            .parse("mode = symbolic # for the US team\n\nNumpad5 = TEN 5\nLShift = SHIFT\n")
+            .unwrap();
+        assert_eq!(keymap.mode, KeymapMode::Symbolic);
+        assert_eq!(keymap.bindings.len(), 2);
+        assert!(keymap.parse("Numpad5 = TEN 55").is_err());
+        assert!(keymap.parse("NotAKey = Q").is_err());
+        assert!(keymap.parse("mode = sideways").is_err());
+
+        // Typed characters press the X1 key with the shift they need, then release it
+        keymap.type_char('"', false);
+        keymap.type_char('"', false);
+        keymap.type_char('\u{3}', true);
+        assert_eq!(keymap.next_typed(), (key_held(&["2"]), true));
+        assert_eq!(keymap.next_typed(), (0, false));
+        assert_eq!(keymap.next_typed(), (key_held(&["2"]), true));
+        assert_eq!(keymap.next_typed(), (0, false));
+        assert_eq!(keymap.next_typed(), (key_held(&["C"]), false));
+        assert_eq!(keymap.next_typed(), (0, false));
+        assert_eq!(keymap.next_typed(), (0, false));
+
+        // Each frame scanned takes at most one queued key, whatever the number of frames
+        keymap.type_char('a', false);
+        assert_eq!(keymap.scan(&|_| false), (key_held(&["A"]), 0));
+        assert_eq!(keymap.scan(&|_| false), (0, 0));
+        assert_eq!(keymap.scan(&|_| false), (0, 0));
+    }
+
+    #[test]
+    fn test_joystick_ports() {
+        let mut io = IO::new(vec![0; 0x1000], vec![0; 0x1000], vec![0; 0x800]);
+        io.set_joysticks([JOY_UP | JOY_TRIGGER_1, JOY_RIGHT]);
+
+        // PSG ports A and B, active low
+        io.write_io(0x1c00, 14, true);
+        assert_eq!(io.peek_io(0x1b00, true), 0xde);
+        io.write_io(0x1c00, 15, true);
+        assert_eq!(io.peek_io(0x1b00, true), 0xf7);
+
+        // Joystick 1 in the game keys, as numpad 8 and Z
+        io.subcpu.keyboard.game_keys = [0x00, 0x00, 0x01];
+        io.set_joysticks([JOY_LEFT | JOY_UP | JOY_TRIGGER_1, 0]);
+        subcpu_send(&mut io.subcpu, &mut io.cmt, &[0xe3]);
+        assert_eq!(subcpu_recv(&mut io.subcpu, 3), [0x04, 0x50, 0x01]);
+    }
+
+    #[test]
+    fn test_joystick_keys_not_shared() {
+        let mut keymap = Keymap::new(Layout::US);
+        let held = |keycode| matches!(keycode, VirtualKeyCode::W | VirtualKeyCode::Return);
+
+        // Without keyboard emulation the keys only reach the X1 keyboard
+        let mut joystick = Joystick::new(JoystickInput::None);
+        let (keys, _, joysticks) = scan_inputs(&mut keymap, &mut joystick, &held);
+        assert_eq!(keys, key_held(&["W", "RETURN"]));
+        assert_eq!(joysticks, [0, 0]);
+
+        // With it, W only moves joystick 2
+        let mut joystick = Joystick::new(JoystickInput::Keyboard);
+        let (keys, _, joysticks) = scan_inputs(&mut keymap, &mut joystick, &held);
+        assert_eq!(keys, key_held(&["RETURN"]));
+        assert_eq!(joysticks, [0, JOY_UP]);
+    }
 }
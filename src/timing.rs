@@ -0,0 +1,134 @@
+use std::time::{Duration, Instant};
+
+const FRAME_RATE: f64 = 60.0;
+// Frames that can be run without being displayed when the host falls behind
+const MAX_FRAME_SKIP: u32 = 4;
+const TURBO_FRAMES: u32 = 10;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Speed {
+    Half,
+    Normal,
+    Double,
+    Turbo,
+}
+
+impl Speed {
+    fn label(&self) -> &'static str {
+        match self {
+            Speed::Half => "50%",
+            Speed::Normal => "100%",
+            Speed::Double => "200%",
+            Speed::Turbo => "Turbo",
+        }
+    }
+
+    fn multiplier(&self) -> Option<f64> {
+        match self {
+            Speed::Half => Some(0.5),
+            Speed::Normal => Some(1.0),
+            Speed::Double => Some(2.0),
+            Speed::Turbo => None,
+        }
+    }
+}
+
+pub struct Timing {
+    pub speed: Speed,
+    next_frame: Instant,
+
+    // Readout, updated every second
+    window_start: Instant,
+    emulated_frames: u32,
+    displayed_frames: u32,
+    pub fps: f64,
+    pub emulation_speed: f64,
+}
+
+impl Timing {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            speed: Speed::Normal,
+            next_frame: now,
+
+            window_start: now,
+            emulated_frames: 0,
+            displayed_frames: 0,
+            fps: 0.0,
+            emulation_speed: 0.0,
+        }
+    }
+
+    pub fn turbo(&self) -> bool {
+        self.speed == Speed::Turbo
+    }
+
+    // When the event loop should wake up for the next frame
+    pub fn next_frame(&self) -> Instant {
+        self.next_frame
+    }
+
+    pub fn frames_due(&mut self, paused: bool) -> u32 {
+        self.frames_due_at(Instant::now(), paused)
+    }
+
+    pub fn frames_due_at(&mut self, now: Instant, paused: bool) -> u32 {
+        let multiplier = match self.speed.multiplier() {
+            Some(multiplier) => multiplier,
+            None if paused => return 0,
+            None => return TURBO_FRAMES,
+        };
+        let frame_time = Duration::from_secs_f64(1.0 / (FRAME_RATE * multiplier));
+        if paused {
+            // Time spent paused isn't caught up on
+            self.next_frame = now + frame_time;
+            return 0;
+        }
+        if now < self.next_frame {
+            return 0;
+        }
+
+        let behind = (now - self.next_frame).as_secs_f64();
+        let mut frames = 1 + (behind / frame_time.as_secs_f64()) as u32;
+        if frames > MAX_FRAME_SKIP + 1 {
+            // Too far behind to catch up, slow down instead
+            frames = MAX_FRAME_SKIP + 1;
+            self.next_frame = now;
+        }
+        self.next_frame += frame_time * frames;
+        frames
+    }
+
+    pub fn frames_done(&mut self, emulated: u32, displayed: bool) {
+        self.emulated_frames += emulated;
+        if displayed {
+            self.displayed_frames += 1;
+        }
+
+        let elapsed = self.window_start.elapsed().as_secs_f64();
+        if elapsed >= 1.0 {
+            self.fps = self.displayed_frames as f64 / elapsed;
+            self.emulation_speed = self.emulated_frames as f64 / elapsed / FRAME_RATE * 100.0;
+            self.window_start = Instant::now();
+            self.emulated_frames = 0;
+            self.displayed_frames = 0;
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.menu_button("Speed", |ui| {
+            for speed in [Speed::Half, Speed::Normal, Speed::Double, Speed::Turbo] {
+                if ui
+                    .radio_value(&mut self.speed, speed, speed.label())
+                    .clicked()
+                {
+                    // Don't try to catch up on time spent at the old speed
+                    self.next_frame = Instant::now();
+                    ui.close_menu();
+                }
+            }
+        });
+        ui.label(format!("{:.0} fps, {:.0}%", self.fps, self.emulation_speed));
+    }
+}
@@ -1,7 +1,19 @@
-use crate::constants::{CPU_CLOCK, DISPLAY_WIDTH, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::constants::{CPU_CLOCK, SCREEN_HEIGHT, SCREEN_WIDTH, VDP_CLOCK};
 use egui::Context;
 
 const PAL_SQUARE_PX: usize = 16;
+// The CRTC gets one character clock per 24 VDP clocks in 80 column mode
+const CHAR_DIVIDER: u64 = 24;
+// Progressive fields shorter than this are 15 kHz ones, and get line doubled for the display
+const LOW_RES_LINES: u16 = 300;
+
+#[derive(Clone, Copy, PartialEq, Savefile)]
+pub enum Deinterlace {
+    // Both fields interleaved into one frame
+    Weave,
+    // Each field line doubled on its own
+    Bob,
+}
 
 #[derive(Savefile)]
 pub struct HD6845S {
@@ -24,19 +36,20 @@ pub struct HD6845S {
 
 impl HD6845S {
     pub fn new() -> Self {
+        // 80x25 as set up by the IPL, so the beam runs before the CRTC is programmed
         Self {
             addr: 0,
-            horiz_char_total: 0,
-            horiz_disp: 0,
-            horiz_sync_pos: 0,
-            sync_width: 0,
-            vert_char_total: 0,
-            vert_total_adj: 0,
-            vert_disp: 0,
-            vert_sync_pos: 0,
+            horiz_char_total: 0x6f,
+            horiz_disp: 0x50,
+            horiz_sync_pos: 0x59,
+            sync_width: 0x38,
+            vert_char_total: 0x1f,
+            vert_total_adj: 0x02,
+            vert_disp: 0x19,
+            vert_sync_pos: 0x1c,
             mode_control: 0,
-            max_ras_addr: 0,
-            cursor_start_ras: 0,
+            max_ras_addr: 0x07,
+            cursor_start_ras: 0x20,
             cursor_end_ras: 0,
             disp_start_addr: 0,
             cursor_addr: 0,
@@ -67,11 +80,52 @@ impl HD6845S {
             _ => panic!("Setting hd6845s addr {:x}", self.addr),
         }
     }
+
+    // Refresh memory address of a character, the start address scrolls both text and graphics
+    pub fn char_addr(&self, row: u16, col: u16) -> u16 {
+        (self.disp_start_addr + row * self.horiz_disp as u16 + col) & 0x3fff
+    }
+
+    pub fn char_height(&self) -> u16 {
+        self.max_ras_addr as u16 + 1
+    }
+
+    /*
+     * Mode control
+     * ---- --xx interlace (0, 2=off, 1=sync only, 3=sync and video)
+     */
+    pub fn interlace_video(&self) -> bool {
+        (self.mode_control & 3) == 3
+    }
+
+    // Lines of a character row in one field, interlaced video splits the rasters between fields
+    fn field_char_height(&self) -> u16 {
+        if self.interlace_video() {
+            (self.char_height() / 2).max(1)
+        } else {
+            self.char_height()
+        }
+    }
+
+    // Raster lines per field, including the vertical total adjust
+    pub fn total_lines(&self) -> u16 {
+        (self.vert_char_total as u16 + 1) * self.field_char_height() + self.vert_total_adj as u16
+    }
+
+    pub fn visible_lines(&self) -> u16 {
+        self.vert_disp as u16 * self.field_char_height()
+    }
+
+    pub fn vsync_line(&self) -> u16 {
+        self.vert_sync_pos as u16 * self.field_char_height()
+    }
 }
 
 #[derive(Savefile)]
 pub struct Video {
+    // Page accessed by the CPU, and page shown on screen
     bitmapbank2: bool,
+    dispbank2: bool,
     bitmapdata0: [u8; 0xc000],
     bitmapdata1: [u8; 0xc000],
     pub hd6845s: HD6845S,
@@ -80,16 +134,44 @@ pub struct Video {
     green_pal: u8,
     blue_pal: u8,
     pub pri: u8,
+    // 8255 port C bit 6, 40 columns with 320 pixels
+    pub hres_320: bool,
+    // 8255 port C bit 4
+    pub smooth_scroll: bool,
     pub avram: [u8; 0x800],
     pub tvram: [u8; 0x800],
-    // ank: Vec<u8>,
+    // 8x16 ANK characters for 16 raster text
+    pub ank: [u8; 0x1000],
     pub fnt: [u8; 0x1800],
     pub pcg_ram: [u8; 0x1800],
-    pub cycles: u32,
     frame_cnt: u8,
 
+    // Beam position, dot_acc counts VDP clocks scaled by CPU_CLOCK into the current line
+    dot_acc: u64,
+    line: u16,
+    // Odd field of an interlaced frame
+    field: bool,
+    pub deinterlace: Deinterlace,
+    // Frame being drawn by the beam, and the last completed one, sized from the CRTC.
+    // The pixels aren't kept in save states, restore_frames sizes them again after a load.
+    width: u32,
+    height: u32,
+    #[savefile_ignore]
+    frame: Vec<u8>,
+    last_size: (u32, u32),
+    #[savefile_ignore]
+    last_frame: Vec<u8>,
+    // Cleared by the main loop for frames that won't be displayed, the beam still runs
+    pub render: bool,
+    // Scratch buffers for the line being drawn
+    #[savefile_ignore]
+    text_buf: Vec<Option<u8>>,
+    #[savefile_ignore]
+    line_buf: Vec<u8>,
+
     palettes_open: bool,
     bitmap0_open: bool,
+    bitmap1_open: bool,
     pcgrom_open: bool,
     pcgram_open: bool,
 }
@@ -97,6 +179,7 @@ pub struct Video {
 pub struct VramViewers {
     palettes_canvas: [u8; 8 * PAL_SQUARE_PX * 2 * PAL_SQUARE_PX * 4],
     bitmap0_canvas: [u8; (SCREEN_WIDTH * SCREEN_HEIGHT * 4) as usize],
+    bitmap1_canvas: [u8; (SCREEN_WIDTH * SCREEN_HEIGHT * 4) as usize],
     pcgrom_canvas: [u8; 128 * 128 * 4],
     pcgram_canvas: [u8; 128 * 128 * 4],
 }
@@ -127,6 +210,7 @@ impl VramViewers {
         Self {
             palettes_canvas: [0; 8 * PAL_SQUARE_PX * 2 * PAL_SQUARE_PX * 4],
             bitmap0_canvas: [0; (SCREEN_WIDTH * SCREEN_HEIGHT * 4) as usize],
+            bitmap1_canvas: [0; (SCREEN_WIDTH * SCREEN_HEIGHT * 4) as usize],
             pcgrom_canvas: pcgrom_canvas,
             pcgram_canvas: [0; 128 * 128 * 4],
         }
@@ -155,6 +239,11 @@ impl VramViewers {
         }
     }
 
+    pub fn draw_bitmaps(&mut self, video: &Video) {
+        draw_bitmap(&mut self.bitmap0_canvas, video, &video.bitmapdata0);
+        draw_bitmap(&mut self.bitmap1_canvas, video, &video.bitmapdata1);
+    }
+
     pub fn draw_palettes(&mut self, palettes: [u32; 16]) {
         for i in 0..16 {
             let pal = palettes[i];
@@ -175,6 +264,32 @@ impl VramViewers {
     }
 }
 
+fn draw_bitmap(canvas: &mut [u8], video: &Video, bitmapdata: &[u8; 0xc000]) {
+    let xsize = video.hd6845s.horiz_disp as u16;
+    let ysize = video.hd6845s.vert_disp as u16;
+    for y in 0..ysize.min((SCREEN_HEIGHT / 8) as u16) {
+        for x in 0..xsize.min((SCREEN_WIDTH / 8) as u16) {
+            for yi in 0..8 {
+                let mut gfx_offset = video.hd6845s.char_addr(y, x) & 0x7ff;
+                gfx_offset += yi * 0x800;
+                for xi in 0..8 {
+                    let pen_b = (bitmapdata[gfx_offset as usize + 0x0000] >> (7 - xi)) & 1;
+                    let pen_r = (bitmapdata[gfx_offset as usize + 0x4000] >> (7 - xi)) & 1;
+                    let pen_g = (bitmapdata[gfx_offset as usize + 0x8000] >> (7 - xi)) & 1;
+                    let color = pen_g << 2 | pen_r << 1 | pen_b << 0;
+                    draw_pixel(
+                        canvas,
+                        SCREEN_WIDTH,
+                        (x * 8 + xi) as i16,
+                        (y * 8 + yi) as i16,
+                        video.palettes[color as usize],
+                    );
+                }
+            }
+        }
+    }
+}
+
 fn draw_pixel(canvas: &mut [u8], canvas_width: u32, plotcol: i16, plotrow: i16, color: u32) {
     let offs = ((plotrow as usize) * canvas_width as usize + plotcol as usize) * 4;
     canvas[offs + 0] = ((color >> 24) & 0xff) as u8; // r
@@ -197,59 +312,120 @@ pub fn draw_pcg_tile(
     invert: bool,
     blink: bool,
 ) {
-    // let xstart = ((self.hd6845s.horiz_char_total - self.hd6845s.horiz_sync_pos) as i16 * 8) / 2;
-    // let ystart = ((self.hd6845s.vert_char_total - self.hd6845s.vert_sync_pos) as i16 * 8) / 2;
-
     for yi in 0..8 {
-        for xi in 0..8 {
-            let plotcol = (col as i16) * 8 + xi; // + xstart;
-            let plotrow = (row as i16) * 8 + yi; // + ystart;
-
-            let mut bit_to_check = xi;
-            if double_width {
-                bit_to_check /= 2;
-                if col % 2 == 1 {
-                    bit_to_check += 4;
-                }
+        let mut yoffs = yi;
+        if double_height {
+            yoffs /= 2;
+            if row % 2 == 1 {
+                yoffs += 4;
             }
+        }
 
-            let mut yoffs = yi;
-            if double_height {
-                yoffs /= 2;
-                if row % 2 == 1 {
-                    yoffs += 4;
-                }
-            }
+        // PCG read
+        let tile_offset = ((tile_idx as usize) * 8) + yoffs as usize;
+        let pattern = [
+            fnt[tile_offset + 0x0000],
+            fnt[tile_offset + 0x0800],
+            fnt[tile_offset + 0x1000],
+        ];
+
+        draw_pcg_line(
+            &palettes,
+            canvas,
+            canvas_width,
+            pattern,
+            (row as u16) * 8 + yi as u16,
+            col,
+            pen_mask,
+            double_width,
+            invert,
+            blink,
+        );
+    }
+}
 
-            // PCG read
-            let tile_offset = ((tile_idx as usize) * 8) + yoffs as usize;
+// Text pen of pixel `xi` of a tile raster, None where the text layer is transparent
+fn pcg_pen(
+    pattern: [u8; 3],
+    xi: u8,
+    col: u8,
+    pen_mask: u8,
+    double_width: bool,
+    invert: bool,
+    blink: bool,
+) -> Option<u8> {
+    let mut bit_to_check = xi;
+    if double_width {
+        bit_to_check /= 2;
+        if col % 2 == 1 {
+            bit_to_check += 4;
+        }
+    }
 
-            let pen0 = fnt[tile_offset + 0x0000] >> (7 - bit_to_check) & (pen_mask & 1) >> 0;
-            let pen1 = fnt[tile_offset + 0x0800] >> (7 - bit_to_check) & (pen_mask & 2) >> 1;
-            let pen2 = fnt[tile_offset + 0x1000] >> (7 - bit_to_check) & (pen_mask & 4) >> 2;
-            let mut pen_val = pen0 | (pen1 << 1) | (pen2 << 2);
+    let pen0 = pattern[0] >> (7 - bit_to_check) & (pen_mask & 1) >> 0;
+    let pen1 = pattern[1] >> (7 - bit_to_check) & (pen_mask & 2) >> 1;
+    let pen2 = pattern[2] >> (7 - bit_to_check) & (pen_mask & 4) >> 2;
+    let mut pen_val = pen0 | (pen1 << 1) | (pen2 << 2);
 
-            if blink {
-                pen_val ^= 7
-            }
-            if pen_val == 0 && !invert {
-                continue;
-            }
-            if invert {
-                pen_val ^= 7
-            }
+    if blink {
+        pen_val ^= 7
+    }
+    // Reversed characters are opaque, so their pen 0 is black rather than see-through
+    if pen_val == 0 && !invert {
+        return None;
+    }
+    if invert {
+        pen_val ^= 7
+    }
+    Some(pen_val)
+}
+
+// Draws one raster of a tile, given its blue, red and green pattern bytes, onto canvas row `plotrow`
+fn draw_pcg_line(
+    palettes: &[u32; 16],
+    canvas: &mut [u8],
+    canvas_width: u32,
+    pattern: [u8; 3],
+    plotrow: u16,
+    col: u8,
+    pen_mask: u8,
+    double_width: bool,
+    invert: bool,
+    blink: bool,
+) {
+    for xi in 0..8 {
+        let plotcol = (col as i16) * 8 + xi as i16;
+        if plotcol as u32 >= canvas_width {
+            break;
+        }
 
-            let color = palettes[pen_val as usize];
-            draw_pixel(canvas, canvas_width, plotcol, plotrow, color);
+        if let Some(pen) = pcg_pen(pattern, xi, col, pen_mask, double_width, invert, blink) {
+            draw_pixel(
+                canvas,
+                canvas_width,
+                plotcol,
+                plotrow as i16,
+                palettes[pen as usize],
+            );
         }
     }
 }
 
+// 8x16 ANK characters made from the 8x8 ones, each raster drawn twice
+pub fn double_height_font(fnt: &[u8]) -> Vec<u8> {
+    fnt.iter()
+        .take(0x800)
+        .flat_map(|byte| [*byte, *byte])
+        .collect()
+}
+
 impl Video {
-    pub fn new(
-        // ank: Vec<u8>,
-        fnt: Vec<u8>,
-    ) -> Self {
+    pub fn new(ank: Vec<u8>, fnt: Vec<u8>) -> Self {
+        let mut new_ank = [0; 0x1000];
+        for (i, byte) in ank.iter().take(0x1000).enumerate() {
+            new_ank[i] = *byte;
+        }
+
         let mut new_fnt = [0; 0x1800];
         for thing in 0..=2 {
             for i in 0..=0x7ff {
@@ -259,6 +435,7 @@ impl Video {
 
         let mut video = Self {
             bitmapbank2: false,
+            dispbank2: false,
             bitmapdata0: [0; 0xc000],
             bitmapdata1: [0; 0xc000],
             hd6845s: HD6845S::new(),
@@ -267,16 +444,31 @@ impl Video {
             green_pal: 0,
             blue_pal: 0,
             pri: 0,
+            hres_320: false,
+            smooth_scroll: false,
             avram: [0; 0x800],
             tvram: [0; 0x800],
-            // ank: ank,
+            ank: new_ank,
             fnt: new_fnt,
             pcg_ram: [0; 0x1800],
-            cycles: 0,
             frame_cnt: 0,
 
+            dot_acc: 0,
+            line: 0,
+            field: false,
+            deinterlace: Deinterlace::Weave,
+            width: 0,
+            height: 0,
+            frame: Vec::new(),
+            last_size: (0, 0),
+            last_frame: Vec::new(),
+            render: true,
+            text_buf: Vec::new(),
+            line_buf: Vec::new(),
+
             palettes_open: false,
             bitmap0_open: false,
+            bitmap1_open: false,
             pcgrom_open: false,
             pcgram_open: false,
         };
@@ -294,131 +486,284 @@ impl Video {
             };
             video.palettes[i] = pal;
         }
+        video.resize_frame();
+        video.last_size = (video.width, video.height);
+        video.last_frame = video.frame.clone();
         video
     }
 
-    fn priority_mixer_pri(&self, color: u8) -> u8 {
-        let mut pri_i = 0u8;
-        let mut pri_mask_calc = 1u8;
+    /*
+     * Character row and raster shown on a line. With smooth scroll on, the rasters start
+     * vert_total_adj lines into the first row, so the picture moves up by that many lines
+     * and the start address does the rest.
+     */
+    fn row_raster(&self, line: u16) -> (u16, u16) {
+        let char_height = self.hd6845s.char_height();
+        let mut line = line;
+        if self.hd6845s.interlace_video() {
+            line = line * 2 + self.field as u16;
+        }
+        if self.smooth_scroll {
+            line += self.hd6845s.vert_total_adj as u16 % char_height;
+        }
+        (line / char_height, line % char_height)
+    }
+
+    /*
+     * Composites the graphics under the text of a line, in one pass.
+     * Each pri bit puts its graphics colour in front of the text, else the text
+     * covers it wherever it isn't transparent.
+     */
+    fn mix_line(&self, canvas: &mut [u8], text: &[Option<u8>], line: u16) {
+        let xsize = self.hd6845s.horiz_disp as u16;
+        let (row, raster) = self.row_raster(line);
+        let yi = raster & 7;
+        let bitmapdata = match self.dispbank2 {
+            false => &self.bitmapdata0,
+            true => &self.bitmapdata1,
+        };
 
-        while pri_i < 7 {
-            if (color & 7) == pri_i {
-                break;
+        for x in 0..xsize {
+            let mut gfx_offset = self.hd6845s.char_addr(row, x) & 0x7ff;
+            gfx_offset += yi * 0x800;
+            for xi in 0..8 {
+                let pen_b = (bitmapdata[gfx_offset as usize + 0x0000] >> (7 - xi)) & 1;
+                let pen_r = (bitmapdata[gfx_offset as usize + 0x4000] >> (7 - xi)) & 1;
+                let pen_g = (bitmapdata[gfx_offset as usize + 0x8000] >> (7 - xi)) & 1;
+
+                let color = pen_g << 2 | pen_r << 1 | pen_b << 0;
+                let gfx_in_front = (self.pri >> color) & 1 != 0;
+
+                for dx in 0..self.dot_width() {
+                    let plotcol = (x * 8 + xi) * self.dot_width() + dx;
+                    if plotcol as u32 >= self.width {
+                        return;
+                    }
+                    let pal = match text[plotcol as usize] {
+                        Some(pen) if !gfx_in_front => self.palettes[pen as usize],
+                        _ => self.palettes[color as usize | 8],
+                    };
+                    draw_pixel(canvas, self.width, plotcol as i16, 0, pal);
+                }
             }
+        }
+    }
 
-            pri_i += 1;
-            pri_mask_calc <<= 1;
+    /*
+     * Pattern bytes of one raster of a character, by character height:
+     * up to 15 rasters: 8x8 tiles, the rasters past the 8th are blank (eg 10 raster rows)
+     * 16 and up: ANK characters come from the 8x16 ROM, PCG tiles are doubled vertically
+     */
+    fn tile_pattern(&self, tile_idx: u8, pcg_bank: bool, raster: u16) -> Option<[u8; 3]> {
+        let high_res = self.hd6845s.char_height() >= 16;
+        if high_res && !pcg_bank {
+            if raster >= 16 {
+                return None;
+            }
+            let byte = self.ank[tile_idx as usize * 16 + raster as usize];
+            return Some([byte; 3]);
         }
 
-        pri_mask_calc
+        let yoffs = if high_res { raster / 2 } else { raster };
+        if yoffs >= 8 {
+            return None;
+        }
+        let fnt = if pcg_bank { &self.pcg_ram } else { &self.fnt };
+        let tile_offset = (tile_idx as usize) * 8 + yoffs as usize;
+        Some([
+            fnt[tile_offset + 0x0000],
+            fnt[tile_offset + 0x0800],
+            fnt[tile_offset + 0x1000],
+        ])
     }
 
-    fn draw_gfxbitmap(
-        &mut self,
-        canvas: &mut [u8],
-        xsize: u8,
-        ysize: u8,
-        pri: u8,
-        vram_viewers: &mut VramViewers,
-    ) {
-        // bitmap
-        for row in 0..ysize {
-            for col in 0..xsize {
-                for yi in 0..8 {
-                    for xi in 0..8 {
-                        let x = col as u16;
-                        let y = row as u16;
-                        let mut gfx_offset = ((x + (y * xsize as u16))
-                            + (self.hd6845s.disp_start_addr & 0x3f00))
-                            & 0x7ff;
-                        gfx_offset += yi * 0x800;
-                        let pen_b =
-                            (self.bitmapdata0[gfx_offset as usize + 0x0000] >> (7 - xi)) & 1;
-                        let pen_r =
-                            (self.bitmapdata0[gfx_offset as usize + 0x4000] >> (7 - xi)) & 1;
-                        let pen_g =
-                            (self.bitmapdata0[gfx_offset as usize + 0x8000] >> (7 - xi)) & 1;
-
-                        let color = pen_g << 2 | pen_r << 1 | pen_b << 0;
-
-                        // checkout priority mixing
-                        // TODO: due of this we loop twice, it should be handled at mixing time instead.
-                        let pri_mask_val = self.priority_mixer_pri(color);
-                        if (pri_mask_val & pri) != 0 {
-                            continue;
-                        }
-
-                        // apply partial update
-                        // TODO: not working properly, see top of file
-                        // if(y*(mc6845_tile_height)+yi < cliprect.min_y || y*(mc6845_tile_height)+yi > cliprect.max_y)
-                        //     continue;
-
-                        // TODO: call a fn subset instead of looping for a width/height that is never hit
-
-                        let plotcol = (x * 8 + xi) as i16;
-                        let plotrow = (y * 8 + yi) as i16;
-                        draw_pixel(
-                            canvas,
-                            DISPLAY_WIDTH,
-                            plotcol,
-                            plotrow,
-                            self.palettes[color as usize | 8],
-                        );
-                        draw_pixel(
-                            &mut vram_viewers.bitmap0_canvas,
-                            SCREEN_WIDTH,
-                            plotcol,
-                            plotrow,
-                            self.palettes[color as usize],
-                        );
+    // Text pens of a line, None where the text layer is transparent
+    fn draw_fgtilemap_line(&self, text: &mut [Option<u8>], line: u16) {
+        let xsize = self.hd6845s.horiz_disp;
+        let char_height = self.hd6845s.char_height();
+        let (row, raster) = self.row_raster(line);
+
+        for col in 0..xsize {
+            let char_addr = self.hd6845s.char_addr(row, col as u16);
+            let tile_offs = (char_addr & 0x7ff) as usize;
+            let tile_idx = self.tvram[tile_offs];
+
+            let attr_byte = self.avram[tile_offs];
+            let double_width = (attr_byte & 0x80) != 0;
+            let double_height = (attr_byte & 0x40) != 0;
+            let pcg_bank = (attr_byte & 0x20) != 0;
+            let blink = (attr_byte & 0x10) != 0 && (self.frame_cnt & 0x10) != 0;
+            let invert = (attr_byte & 0x08) != 0;
+            let color = attr_byte & 7;
+
+            // Double height rows show the top half of the character, then the bottom half
+            let mut yoffs = raster;
+            if double_height {
+                yoffs = (raster + (row % 2) * char_height) / 2;
+            }
+            let pattern = self.tile_pattern(tile_idx, pcg_bank, yoffs);
+            let cursor = char_addr == self.hd6845s.cursor_addr && self.cursor_on(raster);
+
+            for xi in 0..8 {
+                let mut pen = pattern.and_then(|pattern| {
+                    pcg_pen(pattern, xi, col, color, double_width, invert, blink)
+                });
+                // The cursor is a solid block over its rasters, in the colour of the character
+                if cursor && color != 0 {
+                    pen = Some(color);
+                }
+
+                let plotcol = (col as usize * 8 + xi as usize) * self.dot_width() as usize;
+                for dx in 0..self.dot_width() as usize {
+                    if let Some(slot) = text.get_mut(plotcol + dx) {
+                        *slot = pen;
                     }
                 }
             }
         }
     }
 
-    fn draw_fgtilemap(&mut self, canvas: &mut [u8], xsize: u8, ysize: u8) {
-        // tile row and tile col
-        for row in 0..ysize {
-            for col in 0..xsize {
-                let tile_offs = (row as usize) * (xsize as usize) + col as usize;
-                let tile_idx = self.tvram[tile_offs];
-
-                let attr_byte = self.avram[tile_offs];
-                let double_width = (attr_byte & 0x80) != 0;
-                let double_height = (attr_byte & 0x40) != 0;
-                let pcg_bank = (attr_byte & 0x20) != 0;
-                let blink = (attr_byte & 0x10) != 0 && (self.frame_cnt & 0x10) != 0;
-                let invert = (attr_byte & 0x08) != 0;
-                let color = attr_byte & 7;
+    /*
+     * Cursor start raster
+     * -xx- ---- blink mode (0=steady, 1=off, 2=1/16 field rate, 3=1/32 field rate)
+     * ---x xxxx start raster
+     */
+    fn cursor_on(&self, raster: u16) -> bool {
+        let start = (self.hd6845s.cursor_start_ras & 0x1f) as u16;
+        let end = self.hd6845s.cursor_end_ras as u16;
+        if raster < start || raster > end {
+            return false;
+        }
+        match (self.hd6845s.cursor_start_ras >> 5) & 3 {
+            0 => true,
+            1 => false,
+            2 => (self.frame_cnt & 0x08) == 0,
+            _ => (self.frame_cnt & 0x10) == 0,
+        }
+    }
 
-                draw_pcg_tile(
-                    self.palettes,
-                    canvas,
-                    DISPLAY_WIDTH,
-                    if pcg_bank { self.pcg_ram } else { self.fnt },
-                    tile_idx,
-                    row,
-                    col,
-                    color,
-                    double_width,
-                    double_height,
-                    invert,
-                    blink,
-                );
+    // Renders a scanline from the current VRAM, palette and CRTC state
+    fn draw_line(&mut self, line: u16) {
+        let row_len = self.width as usize * 4;
+        let mut text = std::mem::take(&mut self.text_buf);
+        text.clear();
+        text.resize(self.width as usize, None);
+        self.draw_fgtilemap_line(&mut text, line);
+        let mut line_buf = std::mem::take(&mut self.line_buf);
+        line_buf.clear();
+        line_buf.resize(row_len, 0);
+        self.mix_line(&mut line_buf, &text, line);
+
+        // Weave only fills in the rows of its own field, bob and line doubling fill both
+        let scale = self.line_scale();
+        let mut rows = line * scale..(line + 1) * scale;
+        if self.hd6845s.interlace_video() && self.deinterlace == Deinterlace::Weave {
+            rows.start += self.field as u16;
+            rows.end = rows.start + 1;
+        }
+        for row in rows {
+            let start = row as usize * row_len;
+            if let Some(dest) = self.frame.get_mut(start..start + row_len) {
+                dest.copy_from_slice(&line_buf);
             }
         }
+        self.text_buf = text;
+        self.line_buf = line_buf;
     }
 
-    pub fn display(&mut self, canvas: &mut [u8], vram_viewers: &mut VramViewers) {
-        let xsize = self.hd6845s.horiz_disp;
-        let ysize = self.hd6845s.vert_disp;
+    // Output rows per line of a field
+    fn line_scale(&self) -> u16 {
+        if self.hd6845s.interlace_video() || self.hd6845s.total_lines() < LOW_RES_LINES {
+            2
+        } else {
+            1
+        }
+    }
+
+    fn resize_frame(&mut self) {
+        let width = (self.hd6845s.horiz_disp as u32 * 8 * self.dot_width() as u32).max(8);
+        let height = (self.hd6845s.visible_lines() as u32 * self.line_scale() as u32).max(1);
+        let len = (width * height * 4) as usize;
+        if (width, height) != (self.width, self.height) || self.frame.len() != len {
+            self.width = width;
+            self.height = height;
+            self.frame = vec![0; len];
+        }
+    }
 
-        self.draw_gfxbitmap(canvas, xsize, ysize, self.pri, vram_viewers);
-        self.draw_fgtilemap(canvas, xsize, ysize);
-        self.draw_gfxbitmap(canvas, xsize, ysize, self.pri ^ 0xff, vram_viewers);
+    // After a state load, the frames come back blank until the beam redraws them
+    pub fn restore_frames(&mut self) {
+        self.resize_frame();
+        let (width, height) = self.last_size;
+        self.last_frame.resize((width * height * 4) as usize, 0);
+    }
 
+    // Output pixels per dot, the 320 mode dot clock is half the 640 one
+    fn dot_width(&self) -> u16 {
+        if self.hres_320 {
+            2
+        } else {
+            1
+        }
+    }
+
+    // One character is CHAR_DIVIDER VDP clocks per dot width, in the units of dot_acc
+    fn char_len(&self) -> u64 {
+        CHAR_DIVIDER * self.dot_width() as u64 * CPU_CLOCK as u64
+    }
+
+    fn line_len(&self) -> u64 {
+        (self.hd6845s.horiz_char_total as u64 + 1) * self.char_len()
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        self.dot_acc += cycles as u64 * VDP_CLOCK as u64;
+        while self.dot_acc >= self.line_len() {
+            self.dot_acc -= self.line_len();
+            self.end_line();
+        }
+    }
+
+    fn end_line(&mut self) {
+        if self.render && self.line < self.hd6845s.visible_lines() {
+            self.draw_line(self.line);
+        }
+
+        self.line += 1;
+        if self.line >= self.hd6845s.total_lines() {
+            self.line = 0;
+            self.end_field();
+        }
+    }
+
+    fn end_field(&mut self) {
+        self.last_frame.clone_from(&self.frame);
+        self.last_size = (self.width, self.height);
         self.frame_cnt = self.frame_cnt.wrapping_add(1);
+        self.field = self.hd6845s.interlace_video() && !self.field;
+        self.resize_frame();
+    }
+
+    // Size of the frame passed to display
+    pub fn frame_size(&self) -> (u32, u32) {
+        self.last_size
+    }
+
+    pub fn display(&mut self, canvas: &mut [u8]) {
+        canvas.copy_from_slice(&self.last_frame);
+    }
+
+    /*
+     * X1turbo screen mode
+     * --x- ---- PCG mode
+     * ---x ---- graphics page accessed by the CPU
+     * ---- x--- graphics page displayed
+     * ---- -x-- ANK select
+     * ---- --xx 400 line mode when both set
+     */
+    pub fn set_scrn(&mut self, value: u8) {
+        self.bitmapbank2 = (value & 0x10) != 0;
+        self.dispbank2 = (value & 0x08) != 0;
     }
 
     pub fn get_bitmap_data(&self, addr: usize) -> u8 {
@@ -435,6 +780,37 @@ impl Video {
         };
     }
 
+    /*
+     * Simultaneous access, each range writes the same offset in the other planes
+     * 0x0000-0x3fff blue, red and green
+     * 0x4000-0x7fff red and green
+     * 0x8000-0xbfff blue and green
+     * 0xc000-0xffff blue and red
+     */
+    pub fn ex_gfxram_w(&mut self, addr: u16, value: u8) {
+        let planes = match addr {
+            0x0000..=0x3fff => 7,
+            0x4000..=0x7fff => 6,
+            0x8000..=0xbfff => 5,
+            _ => 3,
+        };
+        let offs = (addr & 0x3fff) as usize;
+        for plane in 0..3 {
+            if planes & (1 << plane) != 0 {
+                self.set_bitmap_data(offs + plane * 0x4000, value);
+            }
+        }
+    }
+
+    // Extended graphics RAM reads see both pages from the one being accessed
+    pub fn ex_gfxram_r(&self, addr: u16) -> u8 {
+        let offs = addr as usize + if self.bitmapbank2 { 0xc000 } else { 0 };
+        match offs {
+            0x0000..=0xbfff => self.bitmapdata0[offs],
+            _ => self.bitmapdata1[(offs - 0xc000) % 0xc000],
+        }
+    }
+
     pub fn recreate_bg_palettes(&mut self) {
         for i in 0..8usize {
             let r = ((self.red_pal >> i) & 1) as u32;
@@ -460,11 +836,8 @@ impl Video {
         self.recreate_bg_palettes();
     }
 
-    pub fn pcg_w(&mut self, addr: u16, value: u8) {
-        if addr == 0 {
-            panic!("Writing to ANK area");
-        }
-
+    // Offset of the character raster under the beam, as accessed through 0x1400-0x17ff
+    fn pcg_offset(&self) -> usize {
         let mut y_char_size = if self.hd6845s.max_ras_addr + 1 > 8 {
             self.hd6845s.max_ras_addr + 1 - 8
         } else {
@@ -475,28 +848,45 @@ impl Video {
         }
         let offs = self.get_pcg_addr(self.hd6845s.horiz_disp, y_char_size) as usize;
         let mut pcg_offset = self.tvram[offs] as u16 * 8;
-        // println!("pcg_w {:x} {:x} {:x} {:x} {:x}", addr, value, self.hpos(), self.vpos(), pcg_offset);
         pcg_offset += self.vpos() & (y_char_size as u16 - 1);
-        pcg_offset += (addr - 1) * 0x800;
+        (pcg_offset & 0x7ff) as usize
+    }
+
+    pub fn pcg_r(&self, addr: u16) -> u8 {
+        if addr != 0 {
+            return self.pcg_ram[self.pcg_offset() + (addr as usize - 1) * 0x800];
+        }
+
+        // ANK ROM read, from the 8x16 font when the CRTC is set up for it
+        if self.hd6845s.char_height() >= 16 {
+            let offs = self.get_pcg_addr(self.hd6845s.horiz_disp, 16) as usize;
+            return self.ank[self.tvram[offs] as usize * 16 + (self.vpos() & 15) as usize];
+        }
+        self.fnt[self.pcg_offset()]
+    }
+
+    pub fn pcg_w(&mut self, addr: u16, value: u8) {
+        // The ANK area is ROM
+        if addr == 0 {
+            return;
+        }
 
-        self.pcg_ram[pcg_offset as usize] = value;
+        let pcg_offset = self.pcg_offset() + (addr as usize - 1) * 0x800;
+        self.pcg_ram[pcg_offset] = value;
     }
 
     fn get_pcg_addr(&self, width: u8, y_char_size: u8) -> u16 {
         let hbeam = self.hpos() >> 3;
         let vbeam = self.vpos() / (y_char_size as u16);
-        ((hbeam + vbeam * (width as u16)) + (self.hd6845s.disp_start_addr & 0x3f00)) & 0x7ff
+        ((hbeam + vbeam * (width as u16)) + self.hd6845s.disp_start_addr) & 0x7ff
     }
 
     fn hpos(&self) -> u16 {
-        let cyc_per_line: f64 = CPU_CLOCK as f64 / 264.0 / 60.0;
-        let cyc_per_x = cyc_per_line / SCREEN_WIDTH as f64;
-        ((self.cycles as f64 % cyc_per_line) / cyc_per_x) as u16
+        (self.dot_acc / self.char_len()) as u16 * 8
     }
 
     pub fn vpos(&self) -> u16 {
-        let cyc_per_line: f64 = CPU_CLOCK as f64 / 264.0 / 60.0;
-        (self.cycles as f64 / cyc_per_line) as u16
+        self.line
     }
 
     pub fn ui(
@@ -515,6 +905,10 @@ impl Video {
                 self.bitmap0_open = true;
                 ui.close_menu();
             }
+            if ui.button("Bitmap 1").clicked() {
+                self.bitmap1_open = true;
+                ui.close_menu();
+            }
             if ui.button("PCG ROM Viewer").clicked() {
                 self.pcgrom_open = true;
                 ui.close_menu();
@@ -523,6 +917,10 @@ impl Video {
                 self.pcgram_open = true;
                 ui.close_menu();
             }
+            ui.menu_button("Deinterlace", |ui| {
+                ui.radio_value(&mut self.deinterlace, Deinterlace::Weave, "Weave");
+                ui.radio_value(&mut self.deinterlace, Deinterlace::Bob, "Bob");
+            });
         });
 
         egui::Window::new("Palettes")
@@ -553,11 +951,37 @@ impl Video {
                 ui.image(texture, texture.size_vec2());
                 ui.label(format!(
                     "Bitmap 0 src: ${:04x}",
-                    self.hd6845s.disp_start_addr & 0x3f00
+                    self.hd6845s.disp_start_addr
                 ));
                 ui.label(format!("Horiz disp: {:02x}", self.hd6845s.horiz_disp));
                 ui.label(format!("Vert disp: {:02x}", self.hd6845s.vert_disp));
                 ui.label(format!("Pri: {:02x}", self.pri));
+                ui.label(format!(
+                    "CPU access: {}, displayed: {}",
+                    !self.bitmapbank2, !self.dispbank2
+                ));
+            });
+
+        egui::Window::new("Bitmap 1")
+            .open(&mut self.bitmap1_open)
+            .show(ctx, |ui| {
+                let texture: &egui::TextureHandle = tex_handle.insert(ui.ctx().load_texture(
+                    "bitmap1",
+                    egui::ColorImage::from_rgba_unmultiplied(
+                        [SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize],
+                        &vram_viewers.bitmap1_canvas,
+                    ),
+                    Default::default(),
+                ));
+                ui.image(texture, texture.size_vec2());
+                ui.label(format!(
+                    "Bitmap 1 src: ${:04x}",
+                    self.hd6845s.disp_start_addr
+                ));
+                ui.label(format!(
+                    "CPU access: {}, displayed: {}",
+                    self.bitmapbank2, self.dispbank2
+                ));
             });
 
         egui::Window::new("PCG ROM Viewer")
@@ -148,7 +148,10 @@ pub struct Z80 {
     prefix: Prefix,
 
     pub irq_req: bool,
-    curr_irq_data: u8,
+    // Set when an IRQ is accepted and when RETI executes, for the daisy chain to clear
+    pub irq_acked: bool,
+    pub reti_executed: bool,
+    pub curr_irq_data: u8,
     init_pc: u16,
     is_ext: bool,
     is_im0: bool,
@@ -1019,6 +1022,8 @@ impl Z80 {
             prefix: Prefix::NONE,
 
             irq_req: false,
+            irq_acked: false,
+            reti_executed: false,
             curr_irq_data: 0,
             init_pc: 0,
             is_ext: false,
@@ -2603,6 +2608,7 @@ impl Z80 {
 
                             // Check IRQs
                             if self.irq_req && self.iff1 == 1 {
+                                self.irq_acked = true;
                                 self.iff1 = 0;
                                 self.iff2 = 0;
                                 self.halt = false;
@@ -2938,6 +2944,7 @@ impl Z80 {
                     cpu.wz = cpu.pc;
                     cpu.iff1 = cpu.iff2;
                     cpu.irq_req = false;
+                    cpu.reti_executed = true;
                 }),
                 0x4f => Some(|cpu| cpu.r = cpu.a),
                 0x56 | 0x76 => Some(|cpu| cpu.im = 1),